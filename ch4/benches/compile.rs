@@ -0,0 +1,35 @@
+//! Time the compilation of many trivial functions, without finalizing them until the end.
+//!
+//! Run with `cargo bench --bench compile`.
+
+use std::io;
+use std::time::Instant;
+
+use ch4::ast::{BinaryOp, Expr, Function, Prototype};
+use ch4::gen::Generator;
+
+const FUNCTION_COUNT: usize = 10_000;
+
+fn main() {
+    let mut generator = Generator::new();
+    generator.print_ir_to(Box::new(io::sink()));
+    generator.set_verify(false);
+    let functions: Vec<_> = (0..FUNCTION_COUNT)
+        .map(|index| {
+            let body = Expr::binary(BinaryOp::Plus, Expr::variable("x"), Expr::number(index as f64));
+            Function::new(Prototype::new(format!("f{}", index), &["x"]), body)
+        })
+        .collect();
+
+    let start = Instant::now();
+    for function in functions {
+        generator.compile_only(function).expect("compile");
+    }
+    let compilation = start.elapsed();
+    let start = Instant::now();
+    generator.finalize();
+    let finalization = start.elapsed();
+
+    println!("compiled {} functions in {:?} ({:?} per function), finalized in {:?}", FUNCTION_COUNT, compilation,
+        compilation / FUNCTION_COUNT as u32, finalization);
+}
@@ -0,0 +1,67 @@
+//! Count the allocations made while lexing a file where a few identifiers are repeated many times.
+//!
+//! Interned identifiers are only allocated the first time they are seen, so the lexer should make
+//! far fewer allocations than the copy of each identifier made for comparison.
+//!
+//! Run with `cargo bench --bench lexer`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use ch4::lexer::{Lexer, Token};
+
+const DEFINITION_COUNT: usize = 10_000;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn main() {
+    let source: String = (0..DEFINITION_COUNT)
+        .map(|index| format!("def f{}(left right) left * right + left - right\n", index % 10))
+        .collect();
+
+    let mut lexer = Lexer::new(source.as_bytes());
+    let mut identifiers = 0;
+    let start = Instant::now();
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    loop {
+        match lexer.next_token().expect("token") {
+            Token::Eof => break,
+            Token::Identifier(_) => identifiers += 1,
+            _ => (),
+        }
+    }
+    let interned = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    let elapsed = start.elapsed();
+
+    let mut lexer = Lexer::new(source.as_bytes());
+    let mut copies = Vec::with_capacity(identifiers);
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    loop {
+        match lexer.next_token().expect("token") {
+            Token::Eof => break,
+            Token::Identifier(name) => copies.push(name.to_string()),
+            _ => (),
+        }
+    }
+    let copied = ALLOCATIONS.load(Ordering::Relaxed) - before;
+
+    println!("lexed {} identifiers in {:?} with {} allocations ({} when copying each identifier)", identifiers,
+        elapsed, interned, copied);
+}
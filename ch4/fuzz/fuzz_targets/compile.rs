@@ -0,0 +1,55 @@
+//! Compile arbitrary input, which must give errors instead of panicking.
+//!
+//! The code is only compiled, never finalized nor run, since the input can call anything. Run it
+//! from the `ch4` directory with the samples as the seed corpus:
+//!
+//!     cargo fuzz run compile fuzz/corpus/compile tests
+
+#![no_main]
+
+use std::io;
+
+use cranelift_module::Linkage;
+use libfuzzer_sys::fuzz_target;
+
+use ch4::error::Result;
+use ch4::gen::Generator;
+use ch4::lexer::{Lexer, Token};
+use ch4::parser::Parser;
+
+fuzz_target!(|data: &[u8]| {
+    let mut parser = Parser::new(Lexer::new(data));
+    let mut generator = Generator::new();
+    generator.print_ir_to(Box::new(io::sink()));
+    loop {
+        let token =
+            match parser.peek() {
+                Ok(token) => token.clone(),
+                // The lexer skips the invalid char.
+                Err(_) => continue,
+            };
+        let result: Result<()> =
+            match token {
+                Token::Eof => break,
+                Token::Def | Token::Inline =>
+                    parser.definition().and_then(|definition| generator.compile_only(definition)),
+                Token::DocComment(_) => parser.doc_comment(),
+                Token::Extern => parser.extern_().and_then(|prototypes| {
+                    for prototype in &prototypes {
+                        generator.prototype(prototype, Linkage::Import)?;
+                    }
+                    Ok(())
+                }),
+                Token::Include => parser.include().map(|_| ()),
+                // Skip the separators and the REPL commands.
+                Token::Colon | Token::SemiColon => parser.advance().map(|_| ()),
+                _ => parser.toplevel().and_then(|expr| generator.compile_only(expr)),
+            };
+        if result.is_err() {
+            // Skip the rest of the item where the error happened, like the REPL.
+            if parser.recover().is_err() {
+                break;
+            }
+        }
+    }
+});
@@ -1,4 +1,12 @@
-#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+use std::fmt::{self, Display, Formatter};
+use std::rc::Rc;
+
+/// Spelling of an identifier.
+///
+/// The lexer interns the identifiers, so every occurrence of a name shares the same string.
+pub type Name = Rc<str>;
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum BinaryOp {
     LessThan,
     Minus,
@@ -6,22 +14,259 @@ pub enum BinaryOp {
     Times,
 }
 
-#[derive(Debug)]
+impl Display for BinaryOp {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        let spelling =
+            match *self {
+                BinaryOp::LessThan => "<",
+                BinaryOp::Minus => "-",
+                BinaryOp::Plus => "+",
+                BinaryOp::Times => "*",
+            };
+        write!(formatter, "{}", spelling)
+    }
+}
+
+#[derive(Clone, Debug)]
 pub enum Expr {
     Binary(BinaryOp, Box<Expr>, Box<Expr>),
-    Call(String, Vec<Expr>),
+    Call(Name, Vec<Expr>),
+    /// Nested function definition, visible in the expression following `in`.
+    Def(Box<Function>, Box<Expr>),
+    /// Only the value of a number literal is kept: its spelling (like `1.50` or `.5`) is lost
+    /// after lexing, so printing the AST shows the normalized value.
     Number(f64),
-    Variable(String),
+    /// Parameter `$N` of a top-level expression, counted from 1, which makes the expression a
+    /// function of N parameters named like `$1`.
+    PositionalArg(usize),
+    Variable(Name),
+}
+
+/// Print the expression as source code, with every binary operation in parentheses to show the
+/// structure of the tree.
+impl Display for Expr {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        match *self {
+            Expr::Binary(op, ref left, ref right) => write!(formatter, "({} {} {})", left, op, right),
+            Expr::Call(ref name, ref args) => {
+                let args: Vec<_> = args.iter().map(ToString::to_string).collect();
+                write!(formatter, "{}({})", name, args.join(", "))
+            },
+            Expr::Def(ref function, ref body) => write!(formatter, "{} in {}", function, body),
+            Expr::Number(number) => write!(formatter, "{}", number),
+            Expr::PositionalArg(index) => write!(formatter, "${}", index),
+            Expr::Variable(ref name) => write!(formatter, "{}", name),
+        }
+    }
+}
+
+impl Expr {
+    /// Create a binary operation, boxing its operands.
+    pub fn binary(op: BinaryOp, left: Expr, right: Expr) -> Self {
+        Expr::Binary(op, Box::new(left), Box::new(right))
+    }
+
+    /// Create a call to the function named `function_name`.
+    pub fn call<N: Into<Name>>(function_name: N, args: Vec<Expr>) -> Self {
+        Expr::Call(function_name.into(), args)
+    }
+
+    /// Create a number literal.
+    pub fn number(number: f64) -> Self {
+        Expr::Number(number)
+    }
+
+    /// Create a reference to a parameter or a constant.
+    pub fn variable<N: Into<Name>>(name: N) -> Self {
+        Expr::Variable(name.into())
+    }
+
+    /// Get the highest `N` of the positional parameters `$N` referenced in the expression, or 0 if
+    /// there are none.
+    pub fn max_positional_arg(&self) -> usize {
+        match *self {
+            Expr::Binary(_, ref left, ref right) => left.max_positional_arg().max(right.max_positional_arg()),
+            Expr::Call(_, ref args) => args.iter().map(Expr::max_positional_arg).max().unwrap_or(0),
+            Expr::Def(ref function, ref body) => function.body.max_positional_arg().max(body.max_positional_arg()),
+            Expr::Number(_) | Expr::Variable(_) => 0,
+            Expr::PositionalArg(index) => index,
+        }
+    }
+
+    /// Tell whether the expression contains a nested function definition.
+    pub fn contains_definition(&self) -> bool {
+        match *self {
+            Expr::Binary(_, ref left, ref right) => left.contains_definition() || right.contains_definition(),
+            Expr::Call(_, ref args) => args.iter().any(Expr::contains_definition),
+            Expr::Def(..) => true,
+            Expr::Number(_) | Expr::PositionalArg(_) | Expr::Variable(_) => false,
+        }
+    }
+
+    /// Tell whether the expression refers to the variable `name`, outside of the nested functions
+    /// declaring a parameter of the same name.
+    pub fn uses_variable(&self, name: &str) -> bool {
+        match *self {
+            Expr::Binary(_, ref left, ref right) => left.uses_variable(name) || right.uses_variable(name),
+            Expr::Call(_, ref args) => args.iter().any(|arg| arg.uses_variable(name)),
+            Expr::Def(ref function, ref body) => {
+                let shadowed = function.prototype.parameters.iter().any(|parameter| &**parameter == name);
+                (!shadowed && function.body.uses_variable(name)) || body.uses_variable(name)
+            },
+            Expr::Number(_) | Expr::PositionalArg(_) => false,
+            Expr::Variable(ref variable) => &**variable == name,
+        }
+    }
+
+    /// Infer the type of the expression without compiling it.
+    pub fn infer_type(&self) -> Type {
+        match *self {
+            Expr::Def(_, ref body) => body.infer_type(),
+            // Every value is a f64, including the result of comparisons and calls.
+            Expr::Binary(..) | Expr::Call(..) | Expr::Number(_) | Expr::PositionalArg(_) | Expr::Variable(_) =>
+                Type::F64,
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Function {
     pub prototype: Prototype,
     pub body: Expr,
 }
 
-#[derive(Debug)]
+impl Display for Function {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        if self.prototype.inline {
+            write!(formatter, "inline ")?;
+        }
+        write!(formatter, "def {} {}", self.prototype, self.body)
+    }
+}
+
+impl Function {
+    /// Create a function definition, which can be compiled without going through the parser.
+    pub fn new(prototype: Prototype, body: Expr) -> Self {
+        Self {
+            prototype,
+            body,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Prototype {
-    pub function_name: String,
-    pub parameters: Vec<String>,
+    /// Default value of each parameter, written `y = 2`, which only the last parameters can have.
+    ///
+    /// The defaults are evaluated by the caller, in its own scope, for the missing arguments: they
+    /// cannot refer to the parameters of the function, whose names would be looked up in the caller.
+    pub defaults: Vec<Option<Expr>>,
+    /// Text of the `##` comments preceding the declaration.
+    pub doc: Option<String>,
+    pub function_name: Name,
+    /// Whether the body is substituted at the call sites instead of being called, for a function
+    /// declared with `inline def`.
+    pub inline: bool,
+    pub parameters: Vec<Name>,
+    /// Type of each parameter, `f64` unless annotated.
+    pub parameter_types: Vec<Type>,
+    pub return_type: Type,
+}
+
+impl Prototype {
+    /// Create the prototype of an undocumented function taking and returning `f64` values.
+    pub fn new<N: Into<Name>>(function_name: N, parameters: &[&str]) -> Self {
+        Self {
+            defaults: vec![None; parameters.len()],
+            doc: None,
+            function_name: function_name.into(),
+            inline: false,
+            parameters: parameters.iter().map(|&parameter| parameter.into()).collect(),
+            parameter_types: vec![Type::F64; parameters.len()],
+            return_type: Type::F64,
+        }
+    }
+}
+
+impl Display for Prototype {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        let parameters: Vec<_> = self.parameters.iter()
+            .zip(&self.parameter_types)
+            .zip(&self.defaults)
+            .map(|((name, &type_), default)| {
+                let parameter =
+                    match type_ {
+                        Type::F64 => name.to_string(),
+                        _ => format!("{}: {}", name, type_),
+                    };
+                match *default {
+                    Some(ref default) => format!("{} = {}", parameter, default),
+                    None => parameter,
+                }
+            })
+            .collect();
+        write!(formatter, "{}({}): {}", self.function_name, parameters.join(" "), self.return_type)
+    }
+}
+
+/// Get the name of the parameter for the positional parameter `$index`.
+pub fn positional_parameter(index: usize) -> Name {
+    format!("${}", index).into()
+}
+
+/// Type of a value, as written in annotations like the return type of a prototype.
+///
+/// Kaleidoscope values are always `f64`: the other types only describe the C functions declared
+/// with `extern`, and the values are converted when calling them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Type {
+    F64,
+    I32,
+}
+
+impl Type {
+    /// Get the type spelled `name` in an annotation.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "f64" => Some(Type::F64),
+            "i32" => Some(Type::I32),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        match *self {
+            Type::F64 => write!(formatter, "f64"),
+            Type::I32 => write!(formatter, "i32"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn parser(source: &str) -> Parser<&[u8]> {
+        Parser::new(Lexer::new(source.as_bytes()))
+    }
+
+    #[test]
+    fn display_expr() {
+        let expr = parser("1 + 2 * x - f(3, $1)").toplevel().unwrap().body;
+        assert_eq!(expr.to_string(), "((1 + (2 * x)) - f(3, $1))");
+        let expr = parser("def g(y) y * 2 in g(1.5)").toplevel().unwrap().body;
+        assert_eq!(expr.to_string(), "def g(y): f64 (y * 2) in g(1.5)");
+    }
+
+    #[test]
+    fn display_function() {
+        let function = parser("inline def f(x c: i32 y = 2): i32 x + y;").definition().unwrap();
+        assert_eq!(function.to_string(), "inline def f(x c: i32 y = 2): i32 (x + y)");
+        // The printed definition parses back to the same tree.
+        let reparsed = parser(&function.to_string()).definition().unwrap();
+        assert_eq!(reparsed.to_string(), function.to_string());
+    }
 }
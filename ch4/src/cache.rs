@@ -0,0 +1,151 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::ast::Function;
+use crate::error::Result;
+
+/// Outcome of looking up a function in an `ObjectCache`.
+#[derive(Debug, PartialEq)]
+pub enum Lookup {
+    /// An earlier run compiled the same source, whose entry is at this path.
+    Hit(PathBuf),
+    /// The function must be compiled, then stored under this key.
+    Miss(u64),
+}
+
+/// Directory of compiled functions, keyed by a hash of their source, to reuse them across runs.
+///
+/// Only the decision to reuse an entry is implemented: the JIT cannot load machine code, so
+/// `load` always fails and the entries hold the IR of the functions, until an object backend
+/// produces code to store instead.
+pub struct ObjectCache {
+    directory: PathBuf,
+    hits: usize,
+    misses: usize,
+}
+
+impl ObjectCache {
+    /// Use the cache in `directory`, which is created if needed.
+    pub fn new<P: Into<PathBuf>>(directory: P) -> Result<Self> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+        Ok(Self {
+            directory,
+            hits: 0,
+            misses: 0,
+        })
+    }
+
+    /// Number of lookups which found an entry.
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    /// Hash the source of `function`, as printed by `Display`, with a description of the
+    /// `dependencies` of its code, so that the key depends neither on its formatting and comments
+    /// nor on the run.
+    pub fn key(function: &Function, dependencies: &str) -> u64 {
+        fnv1a(format!("{}\n{}", function, dependencies).as_bytes())
+    }
+
+    /// Load the code of the entry at `path`.
+    ///
+    /// This is a stub giving `None`, which makes the caller compile the function again.
+    pub fn load(&self, _path: &Path) -> Option<*const u8> {
+        None
+    }
+
+    /// Tell whether the function whose key is `key` was compiled by an earlier run.
+    pub fn lookup(&mut self, key: u64) -> Lookup {
+        let path = self.path(key);
+        if path.is_file() {
+            self.hits += 1;
+            Lookup::Hit(path)
+        }
+        else {
+            self.misses += 1;
+            Lookup::Miss(key)
+        }
+    }
+
+    /// Number of lookups which did not find an entry.
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.directory.join(format!("{:016x}.clif", key))
+    }
+
+    /// Store `contents` as the entry of `key`.
+    pub fn store(&self, key: u64, contents: &str) -> Result<()> {
+        fs::write(self.path(key), contents)?;
+        Ok(())
+    }
+}
+
+/// Hash `bytes` with 64-bit FNV-1a, which unlike the hasher of the standard library gives the
+/// same value in every run and with every version of Rust.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use crate::ast::Function;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    use super::{Lookup, ObjectCache, fnv1a};
+
+    fn definition(source: &str) -> Function {
+        Parser::new(Lexer::new(source.as_bytes())).definition().unwrap()
+    }
+
+    fn source_key(source: &str) -> u64 {
+        ObjectCache::key(&definition(source), "")
+    }
+
+    #[test]
+    fn hit_and_miss() {
+        let directory = env::temp_dir().join(format!("kaleidoscope-cache-{}", process::id()));
+        let mut cache = ObjectCache::new(&directory).unwrap();
+        let key =
+            match cache.lookup(source_key("def square(x) x * x")) {
+                Lookup::Miss(key) => key,
+                lookup => panic!("unexpected {:?}", lookup),
+            };
+        cache.store(key, "function u0:0").unwrap();
+        let lookup = cache.lookup(source_key("def square(x)\n  x*x # Same source."));
+        let path =
+            match lookup {
+                Lookup::Hit(path) => path,
+                lookup => panic!("unexpected {:?}", lookup),
+            };
+        assert_eq!(cache.load(&path), None);
+        assert!(matches!(cache.lookup(source_key("def square(x) x * x * x")), Lookup::Miss(_)));
+        assert_eq!((cache.hits(), cache.misses()), (1, 2));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn stable_key() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        let square = source_key("def square(x) x * x");
+        assert_eq!(square, source_key("def square(x) x*x # Same source."));
+        assert_eq!(square, fnv1a(b"def square(x): f64 (x * x)\n"));
+        assert_ne!(square, source_key("def square(y) y * y"));
+        let dependencies = "define DEBUG 3ff0000000000000\n";
+        assert_ne!(square, ObjectCache::key(&definition("def square(x) x * x"), dependencies));
+    }
+}
@@ -4,38 +4,156 @@ use std::num::ParseFloatError;
 use std::result;
 
 use cranelift::codegen::CodegenError;
+use cranelift::codegen::verifier::VerifierErrors;
 use cranelift_module::ModuleError;
 
+use crate::lexer::Token;
+
 use self::Error::*;
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// Category of an error, to handle errors programmatically without depending on their message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    ArgCount,
+    Codegen,
+    Io,
+    Lex,
+    Redefinition,
+    Runtime,
+    UndefinedFunction,
+    UndefinedOperator,
+    UndefinedVariable,
+    UnexpectedToken,
+    Usage,
+}
+
 pub enum Error {
     CraneliftCodegen(CodegenError),
     CraneliftModule(ModuleError),
-    FunctionRedef,
+    /// Parameter whose default value refers to another parameter of the same function.
+    DefaultUsesParameter(String, String),
+    /// Parameter whose default value defines a nested function.
+    DefinitionInDefault(String),
+    /// Warning reported as an error because of `--warnings-as-errors`.
+    DeniedWarning(String),
+    EmptyBody(String),
+    EmptyParens,
+    EntryWithParameters(String, usize),
+    EvaluationPanicked,
+    /// Description of what the parser expected and the token it found instead.
+    Expected(String, Token),
+    Finalization(String),
+    FunctionAsValue(String),
     FunctionRedefWithDifferentParams,
+    IncludeCycle(String),
+    InArgument(usize, String, Box<Error>),
+    InRecursiveCall(String, Box<Error>),
+    InvalidOptionValue(String, String),
     Io(io::Error),
+    MalformedNumber(String),
+    MissingOptionValue(String),
+    NumberOutOfRange(String),
     ParseFloat(ParseFloatError),
+    RequiredAfterDefault(String),
+    TooManyErrors(usize),
     UnknownChar(char),
-    Undefined(&'static str),
+    UnknownCommand(String),
+    UnknownOption(String),
+    UnknownType(String),
+    UnsupportedArity(usize),
+    UnsupportedType(String),
+    UnterminatedString,
+    UndefinedFunction(String),
+    UndefinedOperator,
+    UndefinedVariable(String),
     Unexpected(&'static str),
+    UnexpectedTopLevelExpr,
+    Verifier(VerifierErrors),
     WrongArgumentCount,
 }
 
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            CraneliftCodegen(_) | CraneliftModule(_) | DeniedWarning(_) | Verifier(_) => ErrorKind::Codegen,
+            DefaultUsesParameter(..) | DefinitionInDefault(_) | EmptyBody(_) | EmptyParens | Expected(..) |
+                RequiredAfterDefault(_) | Unexpected(_) | UnexpectedTopLevelExpr | UnknownType(_) =>
+                ErrorKind::UnexpectedToken,
+            EvaluationPanicked => ErrorKind::Runtime,
+            TooManyErrors(_) => ErrorKind::Usage,
+            Finalization(_) => ErrorKind::Codegen,
+            FunctionAsValue(_) => ErrorKind::UndefinedVariable,
+            FunctionRedefWithDifferentParams => ErrorKind::Redefinition,
+            IncludeCycle(_) => ErrorKind::Io,
+            InArgument(_, _, ref error) | InRecursiveCall(_, ref error) => error.kind(),
+            InvalidOptionValue(..) | MissingOptionValue(_) | UnknownCommand(_) | UnknownOption(_) => ErrorKind::Usage,
+            Io(_) => ErrorKind::Io,
+            MalformedNumber(_) | NumberOutOfRange(_) | ParseFloat(_) | UnknownChar(_) | UnterminatedString => ErrorKind::Lex,
+            UndefinedFunction(_) => ErrorKind::UndefinedFunction,
+            UndefinedOperator => ErrorKind::UndefinedOperator,
+            UndefinedVariable(_) => ErrorKind::UndefinedVariable,
+            EntryWithParameters(..) | UnsupportedArity(_) | WrongArgumentCount => ErrorKind::ArgCount,
+            UnsupportedType(_) => ErrorKind::Codegen,
+        }
+    }
+}
+
 impl Debug for Error {
     fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
         match *self {
             CraneliftCodegen(ref error) => error.fmt(formatter),
             CraneliftModule(ref error) => error.fmt(formatter),
-            FunctionRedef => write!(formatter, "redefinition of function"),
+            DefaultUsesParameter(ref name, ref parameter) =>
+                write!(formatter, "the default value of parameter `{}` is evaluated by the caller, so it cannot refer \
+                    to parameter `{}`", name, parameter),
+            DefinitionInDefault(ref name) =>
+                write!(formatter, "the default value of parameter `{}` cannot define a function", name),
+            DeniedWarning(ref warning) => write!(formatter, "{} (warnings are errors)", warning),
+            EmptyBody(ref name) => write!(formatter, "function `{}` has no body", name),
+            EmptyParens => write!(formatter, "empty parentheses, expecting an expression"),
+            EntryWithParameters(ref name, count) =>
+                write!(formatter, "entry point `{}` must take no parameters, but takes {}", name, count),
+            EvaluationPanicked => write!(formatter, "evaluation panicked"),
+            Expected(ref expected, ref found) => write!(formatter, "expected {}, found {}", expected, found),
+            Finalization(ref name) =>
+                write!(formatter, "function `{}` is not finalized yet (call `finalize` after `compile_only`)", name),
+            FunctionAsValue(ref name) => write!(formatter, "`{}` is a function and must be called", name),
             FunctionRedefWithDifferentParams =>
                 write!(formatter, "redefinition of function with different number of parameters"),
+            IncludeCycle(ref path) => write!(formatter, "`{}` includes itself", path),
+            InArgument(index, ref function, ref error) =>
+                write!(formatter, "in argument {} of `{}`: {:?}", index, function, error),
+            InRecursiveCall(ref function, ref error) =>
+                write!(formatter, "in recursive call to `{}`: {:?}", function, error),
+            InvalidOptionValue(ref option, ref value) =>
+                write!(formatter, "invalid value `{}` for option `{}`", value, option),
             Io(ref error) => error.fmt(formatter),
+            MalformedNumber(ref literal) => write!(formatter, "invalid number literal `{}`", literal),
+            MissingOptionValue(ref option) => write!(formatter, "missing value for option `{}`", option),
+            NumberOutOfRange(ref literal) => write!(formatter, "number `{}` is out of range", literal),
             ParseFloat(ref error) => error.fmt(formatter),
+            RequiredAfterDefault(ref name) =>
+                write!(formatter, "parameter `{}` needs a default value since it follows a parameter with one", name),
+            TooManyErrors(count) =>
+                write!(formatter, "aborting after {} error{}", count, if count == 1 { "" } else { "s" }),
             UnknownChar(char) => write!(formatter, "unknown char `{}`", char),
-            Undefined(msg) => write!(formatter, "undefined {}", msg),
+            UnknownCommand(ref name) => write!(formatter, "unknown command `:{}`", name),
+            UnknownOption(ref option) => write!(formatter, "unknown option `{}`", option),
+            UnknownType(ref name) => write!(formatter, "unknown type `{}`", name),
+            UnsupportedArity(arity) => write!(formatter, "cannot call a function with {} parameters", arity),
+            UnsupportedType(ref name) =>
+                write!(formatter, "function `{}` must only use f64: other types are only allowed in `extern`", name),
+            UnterminatedString => write!(formatter, "unterminated string, expecting `\"`"),
+            UndefinedFunction(ref name) =>
+                write!(formatter, "undefined function `{}` (declare it with `extern` or `def` before calling it)", name),
+            UndefinedOperator => write!(formatter, "undefined operator"),
+            UndefinedVariable(ref name) => write!(formatter, "undefined variable `{}`", name),
             Unexpected(msg) => write!(formatter, "unexpected {}", msg),
+            UnexpectedTopLevelExpr =>
+                write!(formatter, "top-level expressions are not allowed in strict mode, only `def` and `extern`"),
+            Verifier(ref errors) => write!(formatter, "invalid IR: {}", errors),
             WrongArgumentCount => write!(formatter, "wrong argument count"),
         }
     }
@@ -64,3 +182,26 @@ impl From<ModuleError> for Error {
         CraneliftModule(error)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::testutil::run;
+
+    use super::ErrorKind;
+
+    fn kind(source: &str) -> ErrorKind {
+        run(source, "f", &[]).unwrap_err().kind()
+    }
+
+    #[test]
+    fn kinds() {
+        assert_eq!(kind("def f() 1 ? 2"), ErrorKind::Lex);
+        assert_eq!(kind("def f() (1"), ErrorKind::UnexpectedToken);
+        assert_eq!(kind("def f() x"), ErrorKind::UndefinedVariable);
+        assert_eq!(kind("def f() g()"), ErrorKind::UndefinedFunction);
+        assert_eq!(kind("def g(x) x; def f() g()"), ErrorKind::ArgCount);
+        assert_eq!(kind("def g(x) x; def g(x y) x"), ErrorKind::Redefinition);
+        // The kind of an error in an argument is the kind of the underlying error.
+        assert_eq!(kind("def g(x) x; def f() g(y)"), ErrorKind::UndefinedVariable);
+    }
+}
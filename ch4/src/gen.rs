@@ -1,8 +1,15 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::f64::consts;
+use std::hash::{Hash, Hasher};
+use std::io::{Write, stdout};
 use std::mem;
+use std::rc::Rc;
 use std::str::FromStr;
 
-use cranelift::codegen::ir::InstBuilder;
+use cranelift::codegen::cfg_printer::CFGPrinter;
+use cranelift::codegen::ir::{self, InstBuilder};
+use cranelift::codegen::verify_function;
 use cranelift::codegen::settings::Configurable;
 use cranelift::prelude::{
     AbiParam,
@@ -10,6 +17,7 @@ use cranelift::prelude::{
     FloatCC,
     FunctionBuilder,
     FunctionBuilderContext,
+    TrapCode,
     Value,
     Variable,
     isa,
@@ -29,43 +37,289 @@ use crate::ast::{
     BinaryOp,
     Expr,
     Function,
+    Name,
     Prototype,
+    Type,
+    positional_parameter,
 };
+use crate::cache::{Lookup, ObjectCache};
 use crate::error::Result;
 use crate::error::Error::*;
+use crate::lexer::{Lexer, Token};
+use crate::parser::Parser;
+use crate::runtime;
+use crate::visit::{self, Visitor};
 
 pub struct Generator {
+    /// Number of top-level expressions compiled, to give them unique names.
+    anonymous_count: usize,
     builder_context: FunctionBuilderContext,
-    functions: HashMap<String, CompiledFunction>,
+    /// Compiled functions by name, with the hash of their source, when caching is enabled.
+    cache: Option<HashMap<Name, (u64, FunctionHandle)>>,
+    /// Values of the names that are neither parameters nor functions.
+    constants: HashMap<String, f64>,
+    /// Values defined by the host, like `--define` on the command line.
+    defines: HashMap<String, f64>,
+    /// Sorted by name so that `dump_ir` is deterministic.
+    functions: BTreeMap<Name, CompiledFunction>,
+    ir_output: Box<dyn Write>,
+    last_result: Option<f64>,
     module: Module<SimpleJITBackend>,
+    /// Name of the module whose function is being compiled, if any.
+    namespace: Option<String>,
+    /// Cache of the definitions compiled by the previous runs.
+    object_cache: Option<ObjectCache>,
+    /// Host functions callable with `extern`, in addition to the runtime.
+    symbols: Vec<(String, *const u8)>,
     variable_builder: VariableBuilder,
+    verify: bool,
 }
 
 impl Generator {
     pub fn new() -> Self {
-        let mut flag_builder = settings::builder();
-        flag_builder.set("opt_level", "best").expect("set optlevel");
-        let isa_builder = isa::lookup(triple!("x86_64-unknown-unknown-elf")).expect("isa");
-        let isa = isa_builder.finish(settings::Flags::new(flag_builder));
+        Self::with_symbols(vec![])
+    }
+
+    /// Create a generator whose `extern` declarations can call the host functions in `symbols`, in
+    /// addition to the runtime.
+    ///
+    /// Each function, given by its name and its address, must have the signature of its declaration
+    /// and the C calling convention. The JIT only reads its symbol table when creating its module,
+    /// which is why they can only be given at construction.
+    pub fn with_symbols(symbols: Vec<(String, *const u8)>) -> Self {
         Self {
+            anonymous_count: 0,
             builder_context: FunctionBuilderContext::new(),
-            functions: HashMap::new(),
-            module: Module::new(SimpleJITBuilder::with_isa(isa)),
+            cache: None,
+            constants: HashMap::new(),
+            defines: HashMap::new(),
+            functions: BTreeMap::new(),
+            ir_output: Box::new(stdout()),
+            last_result: None,
+            module: new_module(&symbols),
+            namespace: None,
+            object_cache: None,
+            symbols,
             variable_builder: VariableBuilder::new(),
+            verify: cfg!(debug_assertions),
+        }
+    }
+
+    /// Make `name` evaluate to `value` in the functions compiled afterwards, unless shadowed by a
+    /// parameter or a constant.
+    pub fn define_constant(&mut self, name: &str, value: f64) {
+        self.defines.insert(name.to_string(), value);
+        self.invalidate_cache();
+    }
+
+    /// Get a handle to compile functions in the module `name`.
+    ///
+    /// The functions of a module do not collide with the functions of the same name of other
+    /// modules, nor with the global functions, which they hide. A module can still call the
+    /// global functions and externs, but not the functions of other modules.
+    pub fn module(&mut self, name: &str) -> ModuleHandle<'_> {
+        ModuleHandle {
+            generator: self,
+            name: name.to_string(),
+        }
+    }
+
+    /// Forget every function, so that the next compilation starts from a clean state.
+    ///
+    /// The JIT never frees the code compiled so far, which is leaked along with the old module:
+    /// the handles previously returned keep calling the functions as they were before the reset.
+    pub fn reset(&mut self) {
+        self.last_result = None;
+        self.invalidate_cache();
+        self.functions.clear();
+        self.module = new_module(&self.symbols);
+        self.variable_builder = VariableBuilder::new();
+    }
+
+    /// Compile a definition, either parsed or built with the constructors of the `ast` module.
+    ///
+    /// Redefining a function declares a fresh symbol for the new body: code compiled afterwards
+    /// calls the new version, while already compiled callers keep calling the old one. The JIT
+    /// cannot free the old code, so it is leaked.
+    pub fn function(&mut self, mut function: Function) -> Result<FunctionHandle> {
+        if function.prototype.function_name.starts_with("__anon_") {
+            // Every parser numbers its expressions from 1, so the prelude and included files would
+            // otherwise reuse the names of the symbols already defined in the module.
+            self.anonymous_count += 1;
+            function.prototype.function_name = format!("__anon_{}", self.anonymous_count).into();
+        }
+        let function_name = function.prototype.function_name.clone();
+        let arity = function.prototype.parameters.len();
+        let key = self.cache_key(&function);
+        if let (Some(cache), Some(key)) = (&self.cache, key) {
+            if let Some(&(hash, handle)) = cache.get(&function_name) {
+                if hash == key {
+                    return Ok(handle);
+                }
+            }
+        }
+        let object_key = self.object_cache_key(&function);
+        let lookup =
+            match (&mut self.object_cache, object_key) {
+                (Some(cache), Some(key)) => Some(cache.lookup(key)),
+                _ => None,
+            };
+        let func_id = self.define(function)?;
+        self.finalize();
+        if let Some(function) = self.functions.get_mut(&function_name) {
+            function.object_key = object_key;
+        }
+        // The JIT cannot load the cached code (see `ObjectCache::load`), so the function is compiled
+        // even on a hit, without replacing its entry.
+        if let (Some(cache), Some(Lookup::Miss(key))) = (&self.object_cache, lookup) {
+            if let Some(ir) = self.functions.get(&function_name).and_then(|function| function.ir.as_ref()) {
+                cache.store(key, ir)?;
+            }
+        }
+
+        if function_name.starts_with("__anon_") {
+            self.functions.remove(&*function_name);
         }
+
+        let handle = FunctionHandle {
+            arity,
+            pointer: self.finalized_function(&function_name, func_id)?,
+        };
+        if let (Some(cache), Some(key)) = (&mut self.cache, key) {
+            cache.insert(function_name, (key, handle));
+        }
+        Ok(handle)
+    }
+
+    /// Hash the source of `function` to look it up in the cache, or give `None` if it must be
+    /// compiled anyway.
+    fn cache_key(&self, function: &Function) -> Option<u64> {
+        let anonymous = function.prototype.function_name.starts_with("__anon_");
+        if self.cache.is_none() || !self.constants.is_empty() || anonymous {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        format!("{:?}", function).hash(&mut hasher);
+        let mut free = vec![];
+        free_variables(&function.body, &function.prototype.parameters, &HashMap::new(), &mut free);
+        if free.iter().any(|name| &**name == "_") {
+            // The value of `_` is copied in the code, so it is part of the source.
+            self.last_result.map(f64::to_bits).hash(&mut hasher);
+        }
+        Some(hasher.finish())
+    }
+
+    /// Hash the source of `function` to look it up in the object cache, or give `None` if it must
+    /// not be cached.
+    ///
+    /// The code also depends on the defines and on the functions it calls, whose keys or signatures
+    /// are hashed with the source. Like with `cache_key`, the expressions and the functions whose
+    /// code depends on constants or on `_` are never cached, nor are their callers.
+    fn object_cache_key(&self, function: &Function) -> Option<u64> {
+        let anonymous = function.prototype.function_name.starts_with("__anon_");
+        if self.object_cache.is_none() || !self.constants.is_empty() || anonymous {
+            return None;
+        }
+        let mut free = vec![];
+        free_variables(&function.body, &function.prototype.parameters, &HashMap::new(), &mut free);
+        if free.iter().any(|name| &**name == "_") {
+            return None;
+        }
+
+        let mut dependencies = String::new();
+        let defines: BTreeMap<_, _> = self.defines.iter().collect();
+        for (name, value) in defines {
+            dependencies.push_str(&format!("define {} {:016x}\n", name, value.to_bits()));
+        }
+        let mut callees = Callees(vec![]);
+        visit::walk_function(&mut callees, function);
+        callees.0.sort();
+        for name in callees.0 {
+            let qualified_name = self.namespace.as_ref()
+                .map(|namespace| format!("{}::{}", namespace, name))
+                .filter(|qualified_name| self.functions.contains_key(qualified_name.as_str()));
+            let callee_name = qualified_name.as_deref().unwrap_or(&name);
+            if callee_name == &*function.prototype.function_name {
+                continue;
+            }
+            match self.functions.get(callee_name) {
+                Some(callee) if callee.defined =>
+                    dependencies.push_str(&format!("call {} {:016x}\n", callee_name, callee.object_key?)),
+                Some(callee) => dependencies.push_str(&format!("extern {} {:?} {:?}\n", callee_name,
+                    callee.parameter_types, callee.return_type)),
+                // A built-in or a nested function, whose source is part of the one of `function`.
+                None => (),
+            }
+        }
+        Some(ObjectCache::key(function, &dependencies))
+    }
+
+    /// Compile the definition or expression in `source`, where the names in `constants` evaluate
+    /// to the given values unless shadowed by a parameter.
+    pub fn compile_with_constants(&mut self, source: &str, constants: HashMap<String, f64>)
+        -> Result<FunctionHandle>
+    {
+        let mut parser = Parser::new(Lexer::new(source.as_bytes()));
+        let function =
+            match *parser.peek()? {
+                Token::Def | Token::Inline => parser.definition()?,
+                _ => parser.toplevel()?,
+            };
+        self.constants = constants;
+        let result = self.function(function);
+        self.constants.clear();
+        result
     }
 
-    pub fn function(&mut self, function: Function) -> Result<fn() -> f64> {
+    /// Compile a definition without finalizing it.
+    ///
+    /// This allows measuring the compilation alone, or compiling many functions before making
+    /// them callable all at once with `finalize`.
+    pub fn compile_only(&mut self, function: Function) -> Result<()> {
+        self.define(function)?;
+        Ok(())
+    }
+
+    fn define(&mut self, function: Function) -> Result<FuncId> {
+        let mut lifted = vec![];
+        let function = lift(function, &mut lifted);
+        for nested_function in lifted {
+            self.define(nested_function)?;
+        }
+
+        let prototype = &function.prototype;
+        if prototype.return_type != Type::F64 || prototype.parameter_types.iter().any(|&type_| type_ != Type::F64) {
+            return Err(UnsupportedType(prototype.function_name.to_string()));
+        }
+
+        let function_name = function.prototype.function_name.clone();
+        let previous = self.functions.get(&function_name).cloned();
+        let func_id = self.prototype(&function.prototype, Linkage::Export)?;
+        if let Err(error) = self.define_body(function, func_id) {
+            self.restore(function_name, previous);
+            return Err(error);
+        }
+        Ok(func_id)
+    }
+
+    /// Generate the code of a function already declared as `func_id`.
+    fn define_body(&mut self, function: Function, func_id: FuncId) -> Result<()> {
         let mut context = self.module.make_context();
         let signature = &mut context.func.signature;
         let parameters = &function.prototype.parameters;
         for _parameter in parameters {
             signature.params.push(AbiParam::new(types::F64));
         }
-        signature.returns.push(AbiParam::new(types::F64));
+        signature.returns.push(AbiParam::new(ir_type(function.prototype.return_type)));
 
-        let function_name = function.prototype.function_name.to_string();
-        let func_id = self.prototype(&function.prototype, Linkage::Export)?;
+        let function_name = function.prototype.function_name.clone();
+        let inline =
+            if function.prototype.inline {
+                Some(Rc::new(function.clone()))
+            }
+            else {
+                None
+            };
 
         let mut builder = FunctionBuilder::new(&mut context.func, &mut self.builder_context);
         let entry_block = builder.create_ebb();
@@ -86,80 +340,414 @@ impl Generator {
 
         let mut generator = FunctionGenerator {
             builder,
+            constants: &self.constants,
+            defines: &self.defines,
+            function_name: &function_name,
             functions: &self.functions,
+            last_result: self.last_result,
+            inlining: vec![],
             module: &mut self.module,
+            namespace: self.namespace.as_deref(),
             values,
+            variable_builder: &mut self.variable_builder,
         };
         let return_value =
             match generator.expr(function.body) {
                 Ok(value) => value,
                 Err(error) => {
+                    // The builder requires the block to be terminated even though the function is discarded.
+                    generator.builder.ins().trap(TrapCode::User(0));
                     generator.builder.finalize();
-                    self.functions.remove(&function_name);
                     return Err(error);
                 },
             };
         generator.builder.ins().return_(&[return_value]);
         generator.builder.finalize();
+        self.verify(&context.func)?;
         //optimize(&mut context, &*self.module.isa())?; // FIXME: Cranelift issue #611.
-        println!("{}", context.func.display(None).to_string());
+        let ir = context.func.display(None).to_string();
+        writeln!(self.ir_output, "{}", ir)?;
+
+        let layout = &context.func.layout;
+        let stats = FuncStats {
+            blocks: layout.ebbs().count(),
+            instructions: layout.ebbs().map(|ebb| layout.ebb_insts(ebb).count()).sum(),
+        };
+        if let Some(ref mut function) = self.functions.get_mut(&function_name) {
+            function.cfg_dot = Some(CFGPrinter::new(&context.func).to_string());
+            function.inline = inline;
+            function.ir = Some(ir);
+            function.stats = Some(stats);
+        }
 
         self.module.define_function(func_id, &mut context)?;
         self.module.clear_context(&mut context);
-        self.module.finalize_definitions();
+        Ok(())
+    }
 
-        if function_name.starts_with("__anon_") {
-            self.functions.remove(&function_name);
+    /// Get the IR of every defined function, sorted by name, each preceded by a `; name` comment.
+    ///
+    /// Unlike the IR printed while compiling, this does not depend on the order of the
+    /// definitions, so it can be compared across runs.
+    pub fn dump_ir(&self) -> String {
+        let mut dump = String::new();
+        for (name, function) in &self.functions {
+            if let Some(ref ir) = function.ir {
+                dump.push_str(&format!("; {}\n{}\n", name, ir));
+            }
+        }
+        dump
+    }
+
+    /// Tell what a global name refers to, or `None` if it is unknown.
+    ///
+    /// Variables are local to a function, so they are never found here.
+    pub fn kind_of(&self, name: &str) -> Option<SymbolKind> {
+        self.functions.get(name).map(|function|
+            if function.defined {
+                SymbolKind::Function
+            }
+            else {
+                SymbolKind::Extern
+            })
+    }
+
+    /// Get the default values completing a call to `name` with `args` arguments, which are empty if
+    /// the function is unknown or if the call has all its arguments or too few of them.
+    pub fn missing_defaults(&self, name: &str, args: usize) -> Vec<Expr> {
+        match self.functions.get(name) {
+            Some(function) => {
+                let arity = function.parameter_types.len();
+                let required = arity - function.defaults.len();
+                if args < required || args > arity {
+                    return vec![];
+                }
+                function.defaults[args - required..].to_vec()
+            },
+            None => vec![],
+        }
+    }
+
+    /// Get the code of the function `name` declared as `func_id`, checking that it is not null
+    /// before it can be transmuted and called.
+    fn finalized_function(&mut self, name: &str, func_id: FuncId) -> Result<*const u8> {
+        let pointer = self.module.get_finalized_function(func_id);
+        if pointer.is_null() {
+            return Err(Finalization(name.to_string()));
+        }
+        Ok(pointer)
+    }
+
+    /// Forget the cached functions, whose code might not match their source anymore.
+    fn invalidate_cache(&mut self) {
+        if let Some(ref mut cache) = self.cache {
+            cache.clear();
+        }
+    }
+
+    /// Get a handle to the function `name`, which must be defined and finalized.
+    pub fn lookup(&mut self, name: &str) -> Result<FunctionHandle> {
+        match self.functions.get(name) {
+            Some(function) if function.defined && !function.finalized => Err(Finalization(name.to_string())),
+            Some(function) if function.defined => {
+                let (arity, id) = (function.parameter_types.len(), function.id);
+                Ok(FunctionHandle {
+                    arity,
+                    pointer: self.finalized_function(name, id)?,
+                })
+            },
+            _ => Err(UndefinedFunction(name.to_string())),
+        }
+    }
+
+    /// Get the control-flow graph of the function `name` in the Graphviz DOT format, if it is
+    /// defined.
+    pub fn cfg_dot(&self, name: &str) -> Option<String> {
+        self.functions.get(name).and_then(|function| function.cfg_dot.clone())
+    }
+
+    /// Get the cache given to `set_object_cache`, to know how many definitions it found.
+    pub fn object_cache(&self) -> Option<&ObjectCache> {
+        self.object_cache.as_ref()
+    }
+
+    /// Get the size of the IR generated for the function `name`, if it is defined.
+    pub fn stats(&self, name: &str) -> Option<FuncStats> {
+        self.functions.get(name).and_then(|function| function.stats)
+    }
+
+    /// Finalize the functions compiled with `compile_only`, so that they can be called.
+    pub fn finalize(&mut self) {
+        self.module.finalize_definitions();
+        for function in self.functions.values_mut() {
+            function.finalized = function.defined;
         }
+    }
+
+    /// Put back the function that a failed definition was replacing.
+    fn restore(&mut self, function_name: Name, previous: Option<CompiledFunction>) {
+        match previous {
+            Some(previous) => self.functions.insert(function_name, previous),
+            None => self.functions.remove(&function_name),
+        };
+    }
+
+    /// Make `function` return the already compiled code when called again with the same source,
+    /// instead of compiling a new version.
+    ///
+    /// Redefining a function with a different source invalidates the whole cache, since the
+    /// functions calling it would now call the new version if compiled again.
+    pub fn set_cache(&mut self, enabled: bool) {
+        self.cache =
+            if enabled {
+                Some(HashMap::new())
+            }
+            else {
+                None
+            };
+    }
+
+    /// Store the definitions compiled afterwards in `cache`, to be reused by the next runs.
+    pub fn set_object_cache(&mut self, cache: Option<ObjectCache>) {
+        self.object_cache = cache;
+    }
+
+    /// Make `_` evaluate to `result` in the next compiled functions.
+    ///
+    /// The value is copied in the code when compiling, so a function referring to `_` keeps the
+    /// value it had when it was defined.
+    pub fn set_last_result(&mut self, result: f64) {
+        self.last_result = Some(result);
+    }
+
+    /// Run the Cranelift verifier on each function right after generating its IR.
+    ///
+    /// This is enabled by default in debug builds only.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
 
-        unsafe {
-            Ok(mem::transmute(self.module.get_finalized_function(func_id)))
+    /// Run the verifier on `function` if enabled with `set_verify`.
+    fn verify(&self, function: &ir::Function) -> Result<()> {
+        if self.verify {
+            verify_function(function, self.module.isa()).map_err(Verifier)?;
         }
+        Ok(())
+    }
+
+    /// Write out the IR buffered by the writer given to `print_ir_to`.
+    ///
+    /// The writer is flushed when the generator is dropped, which does not happen when exiting the
+    /// process, so this must be called before `process::exit`.
+    pub fn flush_ir(&mut self) -> Result<()> {
+        self.ir_output.flush()?;
+        Ok(())
     }
 
+    /// Write the IR of the compiled functions to `writer` instead of the standard output.
+    pub fn print_ir_to(&mut self, writer: Box<dyn Write>) {
+        self.ir_output = writer;
+    }
+
+    /// Declare a function in the module.
+    ///
+    /// Only `define` generates IR: declaring an extern writes nothing to the IR output.
     pub fn prototype(&mut self, prototype: &Prototype, linkage: Linkage) -> Result<FuncId> {
         let function_name = &prototype.function_name;
         let parameters = &prototype.parameters;
-        match self.functions.get(function_name) {
-            None => {
-                let mut signature = self.module.make_signature();
-                for _parameter in parameters {
-                    signature.params.push(AbiParam::new(types::F64));
-                }
-                signature.returns.push(AbiParam::new(types::F64));
-
-                let id = self.module.declare_function(&function_name, linkage, &signature)?;
-                self.functions.insert(function_name.to_string(), CompiledFunction {
-                    defined: false,
-                    id,
-                    param_count: parameters.len(),
-                });
-                Ok(id)
-            },
-            Some(function) => {
-                if function.defined {
-                    return Err(FunctionRedef);
-                }
-                if function.param_count != parameters.len() {
-                    return Err(FunctionRedefWithDifferentParams);
+        // Like the parser, reject the prototypes built without it.
+        for (parameter, default) in parameters.iter().zip(&prototype.defaults) {
+            if matches!(*default, Some(ref default) if default.contains_definition()) {
+                return Err(DefinitionInDefault(parameter.to_string()));
+            }
+        }
+        let version =
+            match self.functions.get(function_name) {
+                None => 0,
+                Some(function) => {
+                    if function.parameter_types.len() != parameters.len() {
+                        return Err(FunctionRedefWithDifferentParams);
+                    }
+                    if linkage == Linkage::Import {
+                        return Ok(function.id);
+                    }
+                    if function.defined {
+                        function.version + 1
+                    }
+                    else {
+                        // Defining an extern: declaring its symbol again makes the module export it.
+                        function.version
+                    }
+                },
+            };
+        if version > 0 {
+            self.invalidate_cache();
+        }
+
+        let mut signature = self.module.make_signature();
+        for &type_ in &prototype.parameter_types {
+            signature.params.push(AbiParam::new(ir_type(type_)));
+        }
+        signature.returns.push(AbiParam::new(ir_type(prototype.return_type)));
+
+        // A redefinition gets its own symbol since a function can only be defined once in the module.
+        let symbol =
+            if version == 0 {
+                function_name.to_string()
+            }
+            else {
+                format!("{}.{}", function_name, version)
+            };
+        let id = self.module.declare_function(&symbol, linkage, &signature)?;
+        self.functions.insert(function_name.clone(), CompiledFunction {
+            cfg_dot: None,
+            defaults: prototype.defaults.iter().flatten().cloned().collect(),
+            defined: false,
+            finalized: false,
+            id,
+            inline: None,
+            ir: None,
+            object_key: None,
+            parameter_types: prototype.parameter_types.clone(),
+            return_type: prototype.return_type,
+            stats: None,
+            version,
+        });
+        Ok(id)
+    }
+}
+
+/// Handle to compile functions in a module of a `Generator`.
+pub struct ModuleHandle<'a> {
+    generator: &'a mut Generator,
+    name: String,
+}
+
+impl<'a> ModuleHandle<'a> {
+    /// Compile a definition in this module, where its symbol is prefixed by `module::`.
+    pub fn function(&mut self, mut function: Function) -> Result<FunctionHandle> {
+        let prototype = &mut function.prototype;
+        if !prototype.function_name.starts_with("__anon_") {
+            prototype.function_name = format!("{}::{}", self.name, prototype.function_name).into();
+        }
+        self.generator.namespace = Some(self.name.clone());
+        let result = self.generator.function(function);
+        self.generator.namespace = None;
+        result
+    }
+}
+
+/// Handle to the machine code of a compiled function.
+///
+/// The code is owned by the `Generator` that compiled it and must not be called after it is dropped.
+#[derive(Clone, Copy)]
+pub struct FunctionHandle {
+    arity: usize,
+    pointer: *const u8,
+}
+
+impl FunctionHandle {
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+
+    /// Call the function with `args`, which must match its arity.
+    ///
+    /// Only functions with up to 6 parameters can be called this way.
+    pub fn call(&self, args: &[f64]) -> Result<f64> {
+        if args.len() != self.arity {
+            return Err(WrongArgumentCount);
+        }
+        let pointer = self.pointer;
+        let result =
+            unsafe {
+                match *args {
+                    [] => mem::transmute::<*const u8, extern "C" fn() -> f64>(pointer)(),
+                    [a] => mem::transmute::<*const u8, extern "C" fn(f64) -> f64>(pointer)(a),
+                    [a, b] => mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(pointer)(a, b),
+                    [a, b, c] =>
+                        mem::transmute::<*const u8, extern "C" fn(f64, f64, f64) -> f64>(pointer)(a, b, c),
+                    [a, b, c, d] =>
+                        mem::transmute::<*const u8, extern "C" fn(f64, f64, f64, f64) -> f64>(pointer)(a, b, c, d),
+                    [a, b, c, d, e] =>
+                        mem::transmute::<*const u8, extern "C" fn(f64, f64, f64, f64, f64) -> f64>(pointer)(a, b, c, d, e),
+                    [a, b, c, d, e, f] =>
+                        mem::transmute::<*const u8, extern "C" fn(f64, f64, f64, f64, f64, f64) -> f64>(pointer)(a, b, c, d, e, f),
+                    _ => return Err(UnsupportedArity(self.arity)),
                 }
-                Ok(function.id)
-            },
+            };
+        Ok(result)
+    }
+
+    /// Get the function as a function pointer if it takes no parameters.
+    ///
+    /// The generated code uses the C calling convention of the platform.
+    pub fn nullary(&self) -> Option<extern "C" fn() -> f64> {
+        if self.arity == 0 {
+            unsafe {
+                Some(mem::transmute::<*const u8, extern "C" fn() -> f64>(self.pointer))
+            }
+        }
+        else {
+            None
         }
     }
+
+    pub fn pointer(&self) -> *const u8 {
+        self.pointer
+    }
 }
 
+/// Size of the IR of a function, before optimizations.
+#[derive(Clone, Copy, Debug)]
+pub struct FuncStats {
+    pub blocks: usize,
+    pub instructions: usize,
+}
+
+/// Kind of a global name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymbolKind {
+    /// Declared with `extern` and never defined.
+    Extern,
+    /// Defined with `def`.
+    Function,
+}
+
+#[derive(Clone)]
 struct CompiledFunction {
+    cfg_dot: Option<String>,
+    /// Default values of the last parameters.
+    defaults: Vec<Expr>,
     defined: bool,
+    /// Whether the code was finalized, which `compile_only` leaves to `finalize`.
+    finalized: bool,
     id: FuncId,
-    param_count: usize,
+    /// Definition substituted at the call sites, for an `inline` function.
+    inline: Option<Rc<Function>>,
+    ir: Option<String>,
+    /// Key of the definition in the object cache, if it can be cached.
+    object_key: Option<u64>,
+    parameter_types: Vec<Type>,
+    return_type: Type,
+    stats: Option<FuncStats>,
+    version: usize,
 }
 
 pub struct FunctionGenerator<'a> {
     builder: FunctionBuilder<'a>,
-    functions: &'a HashMap<String, CompiledFunction>,
+    constants: &'a HashMap<String, f64>,
+    defines: &'a HashMap<String, f64>,
+    /// Name of the function being generated.
+    function_name: &'a str,
+    functions: &'a BTreeMap<Name, CompiledFunction>,
+    /// Inline functions being substituted, which are called normally when recursive.
+    inlining: Vec<Name>,
+    last_result: Option<f64>,
     module: &'a mut Module<SimpleJITBackend>,
-    values: HashMap<String, Variable>,
+    namespace: Option<&'a str>,
+    values: HashMap<Name, Variable>,
+    variable_builder: &'a mut VariableBuilder,
 }
 
 impl<'a> FunctionGenerator<'a> {
@@ -167,10 +755,29 @@ impl<'a> FunctionGenerator<'a> {
         let value =
             match expr {
                 Expr::Number(num) => self.builder.ins().f64const(num),
-                Expr::Variable(name) => {
+                Expr::PositionalArg(index) => {
+                    let name = positional_parameter(index);
                     match self.values.get(&name) {
                         Some(&variable) => self.builder.use_var(variable),
-                        None => return Err(Undefined("variable")),
+                        None => return Err(UndefinedVariable(name.to_string())),
+                    }
+                },
+                Expr::Variable(name) => {
+                    match self.values.get(&*name) {
+                        Some(&variable) => self.builder.use_var(variable),
+                        None => {
+                            let last_result = if &*name == "_" { self.last_result } else { None };
+                            let constant = self.constants.get(&*name).cloned()
+                                .or(last_result)
+                                .or_else(|| self.defines.get(&*name).cloned())
+                                .or_else(|| builtin_constant(&name));
+                            match constant {
+                                Some(constant) => self.builder.ins().f64const(constant),
+                                None if self.functions.contains_key(&*name) =>
+                                    return Err(FunctionAsValue(name.to_string())),
+                                None => return Err(UndefinedVariable(name.to_string())),
+                            }
+                        },
                     }
                 },
                 Expr::Binary(op, left, right) => {
@@ -188,23 +795,281 @@ impl<'a> FunctionGenerator<'a> {
                     }
                 },
                 Expr::Call(name, args) => {
-                    match self.functions.get(&name) {
+                    // The functions of the current module hide the global ones.
+                    let qualified_name = self.namespace
+                        .map(|namespace| format!("{}::{}", namespace, name))
+                        .filter(|qualified_name| self.functions.contains_key(qualified_name.as_str()));
+                    let callee_name = qualified_name.as_deref().unwrap_or(&name);
+                    match self.functions.get(callee_name) {
                         Some(func) => {
-                            if func.param_count != args.len() {
+                            let arity = func.parameter_types.len();
+                            let required = arity - func.defaults.len();
+                            if args.len() < required || args.len() > arity {
+                                if callee_name == self.function_name {
+                                    return Err(InRecursiveCall(name.to_string(), Box::new(WrongArgumentCount)));
+                                }
                                 return Err(WrongArgumentCount);
                             }
+                            let arguments = self.arguments(&name, &func.defaults, arity, args)?;
+                            if let Some(ref function) = func.inline {
+                                if callee_name != self.function_name && !self.inlining.iter().any(|name| &**name == callee_name) {
+                                    return self.inline_call(function.clone(), arguments);
+                                }
+                            }
                             let local_func = self.module.declare_func_in_func(func.id, &mut self.builder.func);
-                            let arguments: Result<Vec<_>> = args.into_iter().map(|arg| self.expr(arg)).collect();
-                            let arguments = arguments?;
+                            let arguments: Vec<_> = arguments.into_iter()
+                                .zip(&func.parameter_types)
+                                .map(|(value, &type_)| self.convert_argument(value, type_))
+                                .collect();
                             let call = self.builder.ins().call(local_func, &arguments);
-                            self.builder.inst_results(call)[0]
+                            let result = self.builder.inst_results(call)[0];
+                            self.convert_result(result, func.return_type)
                         },
-                        None => return Err(Undefined("function")),
+                        None if &*name == "between" => self.between(name, args)?,
+                        None if &*name == "sizeof" => self.sizeof(args)?,
+                        None => return Err(UndefinedFunction(name.to_string())),
                     }
                 },
+                Expr::Def(..) => unreachable!("nested definitions are lifted before code generation"),
             };
         Ok(value)
     }
+
+    /// Generate the built-in `between(x, lo, hi)`, which is 1 if `lo <= x <= hi` and 0 otherwise.
+    ///
+    /// Unlike a function, it is only used when no function named `between` is declared. All the
+    /// arguments are evaluated.
+    fn between(&mut self, name: Name, args: Vec<Expr>) -> Result<Value> {
+        if args.len() != 3 {
+            return Err(WrongArgumentCount);
+        }
+        let mut values = vec![];
+        for (index, arg) in args.into_iter().enumerate() {
+            let value = self.expr(arg).map_err(|error| InArgument(index + 1, name.to_string(), Box::new(error)))?;
+            values.push(value);
+        }
+        let (value, low, high) = (values[0], values[1], values[2]);
+        let above_low = self.builder.ins().fcmp(FloatCC::LessThanOrEqual, low, value);
+        let below_high = self.builder.ins().fcmp(FloatCC::LessThanOrEqual, value, high);
+        let boolean = self.builder.ins().band(above_low, below_high);
+        let int = self.builder.ins().bint(types::I32, boolean);
+        Ok(self.builder.ins().fcvt_from_sint(types::F64, int))
+    }
+
+    /// Generate the built-in `sizeof(type)`, which is the size in bytes of a value of `type`, like
+    /// `sizeof(i32)` for 4.
+    ///
+    /// Like `between`, it is only used when no function named `sizeof` is declared.
+    fn sizeof(&mut self, args: Vec<Expr>) -> Result<Value> {
+        let type_ =
+            match *args.as_slice() {
+                [Expr::Variable(ref name)] => Type::from_name(name).ok_or_else(|| UnknownType(name.to_string()))?,
+                [_] => return Err(Unexpected("expression in `sizeof`, expecting a type")),
+                _ => return Err(WrongArgumentCount),
+            };
+        Ok(self.builder.ins().f64const(f64::from(ir_type(type_).bytes())))
+    }
+
+    /// Evaluate the arguments of a call to `name`, completed by the defaults of the missing ones.
+    ///
+    /// The defaults are evaluated in the scope of the caller, after the arguments: they cannot refer
+    /// to the parameters of the callee, which the parser rejects.
+    fn arguments(&mut self, name: &str, defaults: &[Expr], arity: usize, args: Vec<Expr>) -> Result<Vec<Value>> {
+        let required = arity - defaults.len();
+        let missing = defaults[args.len() - required..].iter().cloned();
+        let mut arguments = vec![];
+        for (index, arg) in args.into_iter().chain(missing).enumerate() {
+            let value = self.expr(arg).map_err(|error| InArgument(index + 1, name.to_string(), Box::new(error)))?;
+            arguments.push(value);
+        }
+        Ok(arguments)
+    }
+
+    /// Substitute the body of the inline `function` for a call with the already evaluated `args`.
+    ///
+    /// The arguments are bound to fresh variables: the body only sees its parameters, so the names
+    /// of the caller cannot be captured.
+    fn inline_call(&mut self, function: Rc<Function>, args: Vec<Value>) -> Result<Value> {
+        let mut values = HashMap::new();
+        for (parameter, value) in function.prototype.parameters.iter().zip(args) {
+            let variable = self.variable_builder.create_var(&mut self.builder, value);
+            values.insert(parameter.clone(), variable);
+        }
+        let caller_values = mem::replace(&mut self.values, values);
+        self.inlining.push(function.prototype.function_name.clone());
+        let result = self.expr(function.body.clone());
+        self.inlining.pop();
+        self.values = caller_values;
+        result
+    }
+
+    /// Convert a Kaleidoscope value to an argument of type `type_`, truncating towards zero.
+    ///
+    /// The conversion saturates instead of trapping: values out of the range of `i32` give its
+    /// bounds, and NaN gives 0.
+    fn convert_argument(&mut self, value: Value, type_: Type) -> Value {
+        match type_ {
+            Type::F64 => value,
+            Type::I32 => self.builder.ins().fcvt_to_sint_sat(types::I32, value),
+        }
+    }
+
+    /// Convert a value returned as `type_` to a Kaleidoscope value.
+    fn convert_result(&mut self, value: Value, type_: Type) -> Value {
+        match type_ {
+            Type::F64 => value,
+            Type::I32 => self.builder.ins().fcvt_from_sint(types::F64, value),
+        }
+    }
+}
+
+fn new_module(symbols: &[(String, *const u8)]) -> Module<SimpleJITBackend> {
+    let mut flag_builder = settings::builder();
+    flag_builder.set("opt_level", "best").expect("set optlevel");
+    let isa_builder = isa::lookup(triple!("x86_64-unknown-unknown-elf")).expect("isa");
+    let isa = isa_builder.finish(settings::Flags::new(flag_builder));
+    let mut builder = SimpleJITBuilder::with_isa(isa);
+    builder.symbols(runtime::symbols());
+    builder.symbols(symbols.iter().cloned());
+    Module::new(builder)
+}
+
+/// Value of the mathematical constants, which parameters and constants can shadow.
+fn builtin_constant(name: &str) -> Option<f64> {
+    let value =
+        match name {
+            "e" => consts::E,
+            "pi" => consts::PI,
+            _ => return None,
+        };
+    Some(value)
+}
+
+fn ir_type(type_: Type) -> types::Type {
+    match type_ {
+        Type::F64 => types::F64,
+        Type::I32 => types::I32,
+    }
+}
+
+/// Name and captured variables of a lifted nested function.
+type Capture = (Name, Vec<Name>);
+
+/// Lambda-lift the nested definitions of `function` into top-level functions pushed to `lifted`.
+///
+/// The variables a nested function captures from its enclosing functions are prepended to its
+/// parameters, so that its defaulted parameters stay the last ones, and passed explicitly at every
+/// call site.
+fn lift(function: Function, lifted: &mut Vec<Function>) -> Function {
+    let prototype = function.prototype;
+    let body = lift_expr(function.body, &prototype.function_name, &prototype.parameters, &HashMap::new(), lifted);
+    Function {
+        body,
+        prototype,
+    }
+}
+
+fn lift_expr(expr: Expr, outer_name: &str, scope: &[Name], captures: &HashMap<Name, Capture>,
+    lifted: &mut Vec<Function>) -> Expr
+{
+    match expr {
+        Expr::Binary(op, left, right) => {
+            let left = lift_expr(*left, outer_name, scope, captures, lifted);
+            let right = lift_expr(*right, outer_name, scope, captures, lifted);
+            Expr::Binary(op, Box::new(left), Box::new(right))
+        },
+        Expr::Call(name, args) => {
+            let args: Vec<_> = args.into_iter()
+                .map(|arg| lift_expr(arg, outer_name, scope, captures, lifted))
+                .collect();
+            match captures.get(&name) {
+                Some((lifted_name, captured)) => {
+                    let mut captured_args: Vec<_> = captured.iter().map(|name| Expr::Variable(name.clone())).collect();
+                    captured_args.extend(args);
+                    Expr::Call(lifted_name.clone(), captured_args)
+                },
+                None => Expr::Call(name, args),
+            }
+        },
+        Expr::Def(function, body) => {
+            let Function { prototype, body: function_body } = *function;
+            let mut captured = vec![];
+            free_variables(&function_body, &prototype.parameters, captures, &mut captured);
+            captured.retain(|name| scope.contains(name));
+
+            let doc = prototype.doc;
+            let return_type = prototype.return_type;
+            let lifted_name: Name = format!("{}.{}", outer_name, prototype.function_name).into();
+            let mut captures = captures.clone();
+            captures.insert(prototype.function_name, (lifted_name.clone(), captured.clone()));
+            let mut defaults: Vec<_> = captured.iter().map(|_| None).collect();
+            defaults.extend(prototype.defaults);
+            let mut parameter_types: Vec<_> = captured.iter().map(|_| Type::F64).collect();
+            parameter_types.extend(prototype.parameter_types);
+            let mut parameters = captured;
+            parameters.extend(prototype.parameters);
+            let function_body = lift_expr(function_body, &lifted_name, &parameters, &captures, lifted);
+            lifted.push(Function {
+                body: function_body,
+                prototype: Prototype {
+                    defaults,
+                    doc,
+                    function_name: lifted_name,
+                    inline: false,
+                    parameters,
+                    parameter_types,
+                    return_type,
+                },
+            });
+            lift_expr(*body, outer_name, scope, &captures, lifted)
+        },
+        Expr::Number(_) | Expr::PositionalArg(_) | Expr::Variable(_) => expr,
+    }
+}
+
+fn free_variables(expr: &Expr, bound: &[Name], captures: &HashMap<Name, Capture>, free: &mut Vec<Name>) {
+    let mut add = |name: &Name| {
+        if !bound.contains(name) && !free.contains(name) {
+            free.push(name.clone());
+        }
+    };
+    match *expr {
+        Expr::Binary(_, ref left, ref right) => {
+            free_variables(left, bound, captures, free);
+            free_variables(right, bound, captures, free);
+        },
+        Expr::Call(ref name, ref args) => {
+            if let Some((_, captured)) = captures.get(name) {
+                captured.iter().for_each(&mut add);
+            }
+            for arg in args {
+                free_variables(arg, bound, captures, free);
+            }
+        },
+        Expr::Def(ref function, ref body) => {
+            let mut function_bound = bound.to_vec();
+            function_bound.extend(function.prototype.parameters.iter().cloned());
+            free_variables(&function.body, &function_bound, captures, free);
+            free_variables(body, bound, captures, free);
+        },
+        Expr::Number(_) => (),
+        Expr::PositionalArg(index) => add(&positional_parameter(index)),
+        Expr::Variable(ref name) => add(name),
+    }
+}
+
+/// Collect the names of the functions called by a definition.
+struct Callees(Vec<Name>);
+
+impl Visitor for Callees {
+    fn visit_expr(&mut self, expr: &Expr) {
+        if let Expr::Call(ref name, _) = *expr {
+            if !self.0.contains(name) {
+                self.0.push(name.clone());
+            }
+        }
+        visit::walk_expr(self, expr);
+    }
 }
 
 struct VariableBuilder {
@@ -226,3 +1091,609 @@ impl VariableBuilder {
         variable
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::io::{self, Write};
+    use std::process;
+    use std::rc::Rc;
+
+    use cranelift::codegen::cursor::{Cursor, FuncCursor};
+
+    use crate::ast::{BinaryOp, Expr, Function, Prototype, Type};
+    use crate::cache::ObjectCache;
+    use crate::error::{Error, Result};
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::testutil::run;
+
+    use super::{FunctionHandle, Generator, InstBuilder, Linkage, SymbolKind, ir};
+
+    /// Buffer shared with a generator, to read the IR it writes.
+    #[derive(Clone, Default)]
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Buffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Create a generator discarding its IR.
+    fn generator() -> Generator {
+        let mut generator = Generator::new();
+        generator.print_ir_to(Box::new(io::sink()));
+        generator
+    }
+
+    fn compile(generator: &mut Generator, source: &str) -> Result<FunctionHandle> {
+        generator.compile_with_constants(source, HashMap::new())
+    }
+
+    fn compile_error(generator: &mut Generator, source: &str) -> Error {
+        compile(generator, source).err().expect("compilation error")
+    }
+
+    #[test]
+    fn argument_error() {
+        let mut generator = generator();
+        compile(&mut generator, "def foo(x y) x + y").unwrap();
+        let error = compile_error(&mut generator, "foo(1, x)");
+        assert!(matches!(error, Error::InArgument(2, ref name, ref error)
+            if name == "foo" && matches!(**error, Error::UndefinedVariable(ref name) if name == "x")));
+        assert_eq!(format!("{:?}", error), "in argument 2 of `foo`: undefined variable `x`");
+    }
+
+    #[test]
+    fn between() {
+        let source = "def inside(x) between(x, 1, 3)";
+        let results: Vec<_> = [0.0, 1.0, 2.5, 3.0, 4.0].iter().map(|&x| run(source, "inside", &[x]).unwrap()).collect();
+        assert_eq!(results, [0.0, 1.0, 1.0, 1.0, 0.0]);
+        assert_eq!(run("def between(x lo hi) 42; def f() between(2, 1, 3)", "f", &[]).unwrap(), 42.0);
+        assert!(matches!(run("def f() between(1, 2)", "f", &[]), Err(Error::WrongArgumentCount)));
+    }
+
+    #[test]
+    fn builtin_constants() {
+        assert_eq!(run("def f() pi", "f", &[]).unwrap(), std::f64::consts::PI);
+        assert_eq!(run("def f() e", "f", &[]).unwrap(), std::f64::consts::E);
+        assert_eq!(run("def f(pi) pi * 2", "f", &[1.5]).unwrap(), 3.0);
+        let mut generator = generator();
+        let constants = [("pi".to_string(), 3.0)].iter().cloned().collect();
+        assert_eq!(generator.compile_with_constants("pi", constants).unwrap().call(&[]).unwrap(), 3.0);
+    }
+
+    #[test]
+    fn cache() {
+        let mut generator = Generator::new();
+        let buffer = Buffer::default();
+        generator.print_ir_to(Box::new(buffer.clone()));
+        generator.set_cache(true);
+        let functions = |buffer: &Buffer| buffer.contents().matches("function ").count();
+
+        let first = compile(&mut generator, "def square(x) x * x").unwrap();
+        let second = compile(&mut generator, "def square(x) x * x").unwrap();
+        assert_eq!(first.pointer(), second.pointer());
+        assert_eq!(functions(&buffer), 1);
+
+        let cube = compile(&mut generator, "def square(x) x * x * x").unwrap();
+        assert_ne!(cube.pointer(), first.pointer());
+        assert_eq!(cube.call(&[2.0]).unwrap(), 8.0);
+        // The redefinition invalidated the cache, so the first source is compiled again.
+        let third = compile(&mut generator, "def square(x) x * x").unwrap();
+        assert_ne!(third.pointer(), first.pointer());
+        assert_eq!(functions(&buffer), 3);
+
+        generator.set_cache(false);
+        compile(&mut generator, "def square(x) x * x").unwrap();
+        assert_eq!(functions(&buffer), 4);
+    }
+
+    #[test]
+    fn call_arity() {
+        let mut generator = generator();
+        let function = compile(&mut generator, "def f(x) x * 2").unwrap();
+        assert_eq!(function.arity(), 1);
+        assert!(function.nullary().is_none());
+        assert!(matches!(function.call(&[]), Err(Error::WrongArgumentCount)));
+        assert!(matches!(function.call(&[1.0, 2.0]), Err(Error::WrongArgumentCount)));
+        assert_eq!(function.call(&[4.0]).unwrap(), 8.0);
+        let nullary = compile(&mut generator, "f(1)").unwrap().nullary().unwrap();
+        assert_eq!(nullary(), 2.0);
+    }
+
+    #[test]
+    fn call_before_extern() {
+        let mut generator = generator();
+        let error = compile_error(&mut generator, "foo(1)");
+        assert!(matches!(error, Error::UndefinedFunction(ref name) if name == "foo"));
+        assert!(format!("{:?}", error).contains("declare it with `extern` or `def`"));
+        compile(&mut generator, "def foo(x) x + 1").unwrap();
+        assert_eq!(compile(&mut generator, "foo(1)").unwrap().call(&[]).unwrap(), 2.0);
+    }
+
+    /// Without a conditional there are no branches: every graph has a single node and no edge.
+    #[test]
+    fn cfg_dot() {
+        let mut generator = generator();
+        compile(&mut generator, "def square(x) x * x").unwrap();
+        compile(&mut generator, "def f(x) square(x) + square(x + 1)").unwrap();
+        generator.prototype(&Prototype::new("sin", &["x"]), Linkage::Import).unwrap();
+        for name in &["square", "f"] {
+            let dot = generator.cfg_dot(name).unwrap();
+            assert!(dot.starts_with("digraph"), "{}", dot);
+            assert_eq!(dot.matches("shape=record").count(), 1, "{}", dot);
+            assert_eq!(dot.matches("->").count(), 0, "{}", dot);
+        }
+        assert_eq!(generator.cfg_dot("sin"), None);
+        assert_eq!(generator.cfg_dot("unknown"), None);
+    }
+
+    #[test]
+    fn compile_only() {
+        let mut generator = generator();
+        generator.compile_only(Function::new(Prototype::new("one", &[]), Expr::number(1.0))).unwrap();
+        let body = Expr::binary(BinaryOp::Plus, Expr::call("one", vec![]), Expr::variable("x"));
+        generator.compile_only(Function::new(Prototype::new("inc", &["x"]), body)).unwrap();
+        generator.finalize();
+        assert_eq!(generator.lookup("inc").unwrap().call(&[2.0]).unwrap(), 3.0);
+    }
+
+    #[test]
+    fn compile_with_constants() {
+        let mut generator = generator();
+        let constants = [("a".to_string(), 2.0), ("b".to_string(), 1.0)].iter().cloned().collect();
+        let function = generator.compile_with_constants("def f(x) a*x+b", constants).unwrap();
+        assert_eq!(function.call(&[3.0]).unwrap(), 7.0);
+        // The constants are only visible to this compilation, and the parameters shadow them.
+        let error = compile_error(&mut generator, "def g(x) a*x");
+        assert!(matches!(error, Error::UndefinedVariable(ref name) if name == "a"));
+        let constants = [("x".to_string(), 10.0)].iter().cloned().collect();
+        let function = generator.compile_with_constants("def h(x) x", constants).unwrap();
+        assert_eq!(function.call(&[3.0]).unwrap(), 3.0);
+    }
+
+    /// The defaults are evaluated in the scope of the caller.
+    #[test]
+    fn default_scope() {
+        let source = "def f(x y = a) x + y; def g(a) f(3)";
+        assert_eq!(run(source, "g", &[100.0]).unwrap(), 103.0);
+        let source = "inline def f(x y = a) x + y; def g(a) f(3)";
+        assert_eq!(run(source, "g", &[100.0]).unwrap(), 103.0);
+        let source = "def g(a) def h(x y = a) x + y in h(1)";
+        assert_eq!(run(source, "g", &[10.0]).unwrap(), 11.0);
+        let source = "def f(x y = pi) y; def g() f(1)";
+        assert_eq!(run(source, "g", &[]).unwrap(), std::f64::consts::PI);
+
+        let mut generator = generator();
+        compile(&mut generator, "def f(x y = z) x + y").unwrap();
+        let error = compile_error(&mut generator, "def g(a) f(1)");
+        assert!(matches!(error, Error::InArgument(2, ref name, ref error)
+            if name == "f" && matches!(**error, Error::UndefinedVariable(ref name) if name == "z")));
+        assert_eq!(generator.missing_defaults("f", 1).len(), 1);
+        assert!(generator.missing_defaults("f", 2).is_empty());
+    }
+
+    #[test]
+    fn defaults() {
+        let source = "def f(x y = 2) x + y";
+        assert_eq!(run(source, "f", &[3.0]).unwrap(), 5.0);
+        assert_eq!(run(source, "f", &[3.0, 10.0]).unwrap(), 13.0);
+        let mut generator = generator();
+        compile(&mut generator, source).unwrap();
+        assert!(matches!(compile_error(&mut generator, "f()"), Error::WrongArgumentCount));
+        assert!(matches!(compile_error(&mut generator, "f(1, 2, 3)"), Error::WrongArgumentCount));
+
+        // A default defining a function, which the parser rejects, cannot be built by hand either.
+        let mut prototype = Prototype::new("g", &["x", "y"]);
+        let nested = Function::new(Prototype::new("h", &["z"]), Expr::variable("z"));
+        let call = Expr::call("h", vec![Expr::number(1.0)]);
+        prototype.defaults = vec![None, Some(Expr::Def(Box::new(nested), Box::new(call)))];
+        let error = generator.function(Function::new(prototype, Expr::variable("y"))).err().expect("error");
+        assert!(matches!(error, Error::DefinitionInDefault(ref name) if name == "y"));
+    }
+
+    #[test]
+    fn define_constant() {
+        let mut generator = generator();
+        generator.define_constant("DEBUG", 1.0);
+        assert_eq!(compile(&mut generator, "def f(x) x + DEBUG").unwrap().call(&[2.0]).unwrap(), 3.0);
+        assert_eq!(compile(&mut generator, "def g(DEBUG) DEBUG").unwrap().call(&[5.0]).unwrap(), 5.0);
+        let constants = [("DEBUG".to_string(), 7.0)].iter().cloned().collect();
+        assert_eq!(generator.compile_with_constants("DEBUG", constants).unwrap().call(&[]).unwrap(), 7.0);
+        let error = compile_error(&mut generator, "RELEASE");
+        assert!(matches!(error, Error::UndefinedVariable(ref name) if name == "RELEASE"));
+    }
+
+    #[test]
+    fn define_extern() {
+        let mut generator = generator();
+        generator.prototype(&Prototype::new("f", &["x"]), Linkage::Import).unwrap();
+        compile(&mut generator, "def f(x) x + 1").unwrap();
+        assert_eq!(generator.kind_of("f"), Some(SymbolKind::Function));
+        assert_eq!(compile(&mut generator, "f(2)").unwrap().call(&[]).unwrap(), 3.0);
+        assert_eq!(generator.lookup("f").unwrap().call(&[2.0]).unwrap(), 3.0);
+    }
+
+    #[test]
+    fn dump_ir() {
+        let dump = |sources: &[&str]| {
+            let mut generator = generator();
+            for source in sources {
+                compile(&mut generator, source).unwrap();
+            }
+            generator.dump_ir()
+        };
+        let names = |dump: &str| dump.lines()
+            .filter(|line| line.starts_with(';'))
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        let sources = ["def c() 3", "def a() 1", "def b() a() + c()", "b()"];
+        let first = dump(&sources);
+        assert_eq!(first, dump(&sources));
+        assert_eq!(names(&first), ["; a", "; b", "; c"]);
+        assert_eq!(names(&dump(&["def a() 1", "def c() 3", "def b() a() + c()"])), ["; a", "; b", "; c"]);
+    }
+
+    #[test]
+    fn extern_block() {
+        let mut generator = generator();
+        let mut parser = Parser::new(Lexer::new(&b"extern { sin(x); cos(x)\n pow(x y) }"[..]));
+        let prototypes = parser.extern_().unwrap();
+        assert_eq!(prototypes.len(), 3);
+        for prototype in &prototypes {
+            generator.prototype(prototype, Linkage::Import).unwrap();
+        }
+        for name in &["sin", "cos", "pow"] {
+            assert_eq!(generator.kind_of(name), Some(SymbolKind::Extern));
+        }
+        let mut parser = Parser::new(Lexer::new(&b"extern { sin(x); cos(x)"[..]));
+        assert!(matches!(parser.extern_(), Err(Error::Unexpected(message)) if message.contains("`}`")));
+    }
+
+    #[test]
+    fn extern_writes_no_ir() {
+        let mut generator = Generator::new();
+        let buffer = Buffer::default();
+        generator.print_ir_to(Box::new(buffer.clone()));
+        let mut parser = Parser::new(Lexer::new(&b"extern { sin(x); putchar(c: i32): i32 }"[..]));
+        for prototype in parser.extern_().unwrap() {
+            generator.prototype(&prototype, Linkage::Import).unwrap();
+        }
+        assert_eq!(buffer.contents(), "");
+        assert_eq!(generator.dump_ir(), "");
+    }
+
+    #[test]
+    fn failed_definition() {
+        let mut generator = generator();
+        assert!(matches!(compile_error(&mut generator, "def f(x) y"), Error::UndefinedVariable(_)));
+        assert!(matches!(generator.lookup("f"), Err(Error::UndefinedFunction(_))));
+        assert!(matches!(compile_error(&mut generator, "f(1)"), Error::UndefinedFunction(_)));
+
+        let mut prototype = Prototype::new("putchar", &["c"]);
+        prototype.parameter_types = vec![Type::I32];
+        generator.prototype(&prototype, Linkage::Import).unwrap();
+        assert!(matches!(compile_error(&mut generator, "def putchar(c) c"), Error::CraneliftModule(_)));
+        assert_eq!(generator.kind_of("putchar"), Some(SymbolKind::Extern));
+        assert!(matches!(generator.lookup("putchar"), Err(Error::UndefinedFunction(_))));
+
+        compile(&mut generator, "def g(x) x").unwrap();
+        assert!(matches!(compile_error(&mut generator, "def g(x) y"), Error::UndefinedVariable(_)));
+        assert_eq!(generator.lookup("g").unwrap().call(&[2.0]).unwrap(), 2.0);
+        assert_eq!(compile(&mut generator, "g(3)").unwrap().call(&[]).unwrap(), 3.0);
+        compile(&mut generator, "def g(x) x * 2").unwrap();
+        assert_eq!(compile(&mut generator, "g(3)").unwrap().call(&[]).unwrap(), 6.0);
+    }
+
+    /// Without a conditional, `fib(n)` is built as one function per `n`, each calling the previous two.
+    #[test]
+    fn fibonacci_ast() {
+        let mut generator = generator();
+        generator.function(Function::new(Prototype::new("fib0", &[]), Expr::number(0.0))).unwrap();
+        let mut fib = generator.function(Function::new(Prototype::new("fib1", &[]), Expr::number(1.0))).unwrap();
+        for n in 2..=10 {
+            let previous = |offset: usize| Expr::call(format!("fib{}", n - offset), vec![]);
+            let body = Expr::binary(BinaryOp::Plus, previous(1), previous(2));
+            fib = generator.function(Function::new(Prototype::new(format!("fib{}", n), &[]), body)).unwrap();
+        }
+        assert_eq!(fib.call(&[]).unwrap(), 55.0);
+
+        let square = Expr::binary(BinaryOp::Times, Expr::variable("x"), Expr::variable("x"));
+        let square = generator.function(Function::new(Prototype::new("square", &["x"]), square)).unwrap();
+        assert_eq!(square.call(&[7.0]).unwrap(), 49.0);
+    }
+
+    #[test]
+    fn function_as_value() {
+        let mut generator = generator();
+        compile(&mut generator, "def f(x y) x").unwrap();
+        assert!(matches!(compile_error(&mut generator, "f"), Error::FunctionAsValue(ref name) if name == "f"));
+        assert!(matches!(compile_error(&mut generator, "f + 1"), Error::FunctionAsValue(_)));
+    }
+
+    #[test]
+    fn i32_argument() {
+        extern "C" fn identity(value: i32) -> i32 {
+            value
+        }
+
+        let mut generator = Generator::with_symbols(vec![("identity".to_string(), identity as *const u8)]);
+        generator.print_ir_to(Box::new(io::sink()));
+        let mut parser = Parser::new(Lexer::new(&b"extern identity(value: i32): i32"[..]));
+        let prototype = parser.extern_().unwrap().remove(0);
+        generator.prototype(&prototype, Linkage::Import).unwrap();
+        let mut call = |arg: &str| {
+            compile(&mut generator, &format!("identity({})", arg)).unwrap().call(&[]).unwrap()
+        };
+        assert_eq!(call("72.9"), 72.0);
+        assert_eq!(call("0 - 2.5"), -2.0);
+        assert_eq!(call("nan"), 0.0);
+        assert_eq!(call("inf"), f64::from(i32::MAX));
+        assert_eq!(call("0 - 3000000000"), f64::from(i32::MIN));
+
+        // The symbols given at construction survive a reset.
+        generator.reset();
+        generator.prototype(&prototype, Linkage::Import).unwrap();
+        assert_eq!(compile(&mut generator, "identity(5)").unwrap().call(&[]).unwrap(), 5.0);
+    }
+
+    #[test]
+    fn inline() {
+        let mut generator = Generator::new();
+        let buffer = Buffer::default();
+        generator.print_ir_to(Box::new(buffer.clone()));
+        // Compile a function and get its IR.
+        let ir = |generator: &mut Generator, source: &str| {
+            let start = buffer.contents().len();
+            let function = compile(generator, source).unwrap();
+            (function, buffer.contents()[start..].to_string())
+        };
+        ir(&mut generator, "inline def square(x) x * x");
+        ir(&mut generator, "def square_called(x) x * x");
+        let (sum, inlined) = ir(&mut generator, "def sum(x) square(x) + square(x - 1)");
+        let (sum_called, called) = ir(&mut generator, "def sum_called(x) square_called(x) + square_called(x - 1)");
+        assert!(!inlined.contains("call"), "{}", inlined);
+        assert_eq!(called.matches(" call ").count(), 2, "{}", called);
+        assert_eq!(sum.call(&[3.0]).unwrap(), 13.0);
+        assert_eq!(sum_called.call(&[3.0]).unwrap(), 13.0);
+
+        // The argument is bound to the parameter, even when it refers to a variable of the same name.
+        ir(&mut generator, "inline def twice(x) x + x");
+        let (f, _) = ir(&mut generator, "def f(x) twice(x + 1)");
+        assert_eq!(f.call(&[2.0]).unwrap(), 6.0);
+        // A recursive inline function calls itself instead of being substituted in its own body.
+        let (_, recursive) = ir(&mut generator, "inline def recurse(x) recurse(x - 1)");
+        assert!(recursive.contains(" call "), "{}", recursive);
+    }
+
+    #[test]
+    fn kind_of() {
+        let mut generator = generator();
+        generator.prototype(&Prototype::new("sin", &["x"]), Linkage::Import).unwrap();
+        compile(&mut generator, "def square(x) x * x").unwrap();
+        assert_eq!(generator.kind_of("square"), Some(SymbolKind::Function));
+        assert_eq!(generator.kind_of("sin"), Some(SymbolKind::Extern));
+        assert_eq!(generator.kind_of("x"), None);
+        assert_eq!(generator.kind_of("unknown"), None);
+    }
+
+    #[test]
+    fn last_result() {
+        let mut generator = generator();
+        assert!(matches!(compile_error(&mut generator, "_ * 2"), Error::UndefinedVariable(ref name) if name == "_"));
+        let result = compile(&mut generator, "2+3").unwrap().call(&[]).unwrap();
+        generator.set_last_result(result);
+        assert_eq!(compile(&mut generator, "_*2").unwrap().call(&[]).unwrap(), 10.0);
+        let function = compile(&mut generator, "def f(_) _ + 1").unwrap();
+        assert_eq!(function.call(&[1.0]).unwrap(), 2.0);
+    }
+
+    #[test]
+    fn lookup_before_finalize() {
+        let mut generator = generator();
+        generator.compile_only(Function::new(Prototype::new("one", &[]), Expr::number(1.0))).unwrap();
+        let error = generator.lookup("one").err().expect("error");
+        assert!(matches!(error, Error::Finalization(ref name) if name == "one"));
+        assert_eq!(format!("{:?}", error), "function `one` is not finalized yet (call `finalize` after `compile_only`)");
+        generator.finalize();
+        assert_eq!(generator.lookup("one").unwrap().call(&[]).unwrap(), 1.0);
+    }
+
+    #[test]
+    fn modules() {
+        let mut generator = generator();
+        compile(&mut generator, "def helper(x) x * 10").unwrap();
+        let parse = |source: &str| Parser::new(Lexer::new(source.as_bytes())).definition().unwrap();
+        let first = parse("def main() 1;");
+        let second = parse("def main() helper(2);");
+        let calling_first = parse("def other() main();");
+
+        let first = generator.module("first").function(first).unwrap();
+        let second = generator.module("second").function(second).unwrap();
+        assert_eq!(first.call(&[]).unwrap(), 1.0);
+        assert_eq!(second.call(&[]).unwrap(), 20.0);
+        assert_eq!(generator.lookup("first::main").unwrap().call(&[]).unwrap(), 1.0);
+        assert!(matches!(generator.lookup("main"), Err(Error::UndefinedFunction(_))));
+
+        let error = generator.module("third").function(calling_first).err().expect("error");
+        assert!(matches!(error, Error::UndefinedFunction(ref name) if name == "main"));
+    }
+
+    #[test]
+    fn nested_definition() {
+        assert_eq!(run("def f(x) def g(y) x + y in g(1)", "f", &[2.0]).unwrap(), 3.0);
+        let source = "def f(x) def g(y) def h(z) x * y + z in h(1) in g(3)";
+        assert_eq!(run(source, "f", &[2.0]).unwrap(), 7.0);
+    }
+
+    #[test]
+    fn object_cache() {
+        let directory = env::temp_dir().join(format!("kaleidoscope-object-cache-{}", process::id()));
+        let source = "def square(x) x * x";
+        for &(hits, misses) in &[(0, 1), (1, 0)] {
+            let mut generator = generator();
+            generator.set_object_cache(Some(ObjectCache::new(&directory).unwrap()));
+            assert_eq!(compile(&mut generator, source).unwrap().call(&[3.0]).unwrap(), 9.0);
+            // Neither the expressions nor the functions using constants are cached.
+            compile(&mut generator, "square(2)").unwrap();
+            let constants = [("a".to_string(), 2.0)].iter().cloned().collect();
+            generator.compile_with_constants("def f(x) a * x", constants).unwrap();
+            let cache = generator.object_cache().unwrap();
+            assert_eq!((cache.hits(), cache.misses()), (hits, misses));
+        }
+        let entries = fs::read_dir(&directory).unwrap().count();
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(entries, 1);
+    }
+
+    /// The key depends on the defines and on the definitions of the callees.
+    #[test]
+    fn object_cache_dependencies() {
+        let directory = env::temp_dir().join(format!("kaleidoscope-object-dependencies-{}", process::id()));
+        let lookups = |debug: f64, square: &str| {
+            let mut generator = generator();
+            generator.set_object_cache(Some(ObjectCache::new(&directory).unwrap()));
+            generator.define_constant("DEBUG", debug);
+            compile(&mut generator, square).unwrap();
+            let result = compile(&mut generator, "def f(x) square(x) + DEBUG").unwrap().call(&[3.0]).unwrap();
+            let cache = generator.object_cache().unwrap();
+            (result, cache.hits(), cache.misses())
+        };
+        assert_eq!(lookups(1.0, "def square(x) x * x"), (10.0, 0, 2));
+        assert_eq!(lookups(1.0, "def square(x) x * x"), (10.0, 2, 0));
+        assert_eq!(lookups(0.0, "def square(x) x * x"), (9.0, 0, 2));
+        // `f` is unchanged, but calls another `square`.
+        assert_eq!(lookups(0.0, "def square(x) x + x"), (6.0, 0, 2));
+        assert_eq!(lookups(0.0, "def square(x) x + x"), (6.0, 2, 0));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn positional_arguments() {
+        let mut generator = generator();
+        let square = compile(&mut generator, "$1 * $1").unwrap();
+        assert_eq!(square.arity(), 1);
+        assert_eq!(square.call(&[5.0]).unwrap(), 25.0);
+        let second = compile(&mut generator, "$2").unwrap();
+        assert_eq!(second.arity(), 2);
+        assert_eq!(second.call(&[1.0, 2.0]).unwrap(), 2.0);
+        let error = compile_error(&mut generator, "def f(x) $1");
+        assert!(matches!(error, Error::UndefinedVariable(ref name) if name == "$1"));
+    }
+
+    /// The IR names the functions by their index in the module, like `u0:0`.
+    #[test]
+    fn print_ir_to() {
+        let mut generator = Generator::new();
+        let buffer = Buffer::default();
+        generator.print_ir_to(Box::new(buffer.clone()));
+        compile(&mut generator, "def answer() 42").unwrap();
+        let ir = buffer.contents();
+        assert!(ir.contains("function u0:0() -> f64"), "{}", ir);
+        assert!(ir.contains("f64const 0x1.5000000000000p5"), "{}", ir);
+    }
+
+    #[test]
+    fn recursive_call_arity() {
+        let mut generator = generator();
+        let error = compile_error(&mut generator, "def f(x) f(x, x)");
+        assert!(matches!(error, Error::InRecursiveCall(ref name, _) if name == "f"));
+        assert_eq!(format!("{:?}", error), "in recursive call to `f`: wrong argument count");
+    }
+
+    #[test]
+    fn redefinition() {
+        let mut generator = generator();
+        compile(&mut generator, "def g() 1").unwrap();
+        let old_caller = compile(&mut generator, "def f() g()").unwrap();
+        compile(&mut generator, "def g() 2").unwrap();
+        let new_caller = compile(&mut generator, "def h() g()").unwrap();
+        assert_eq!(old_caller.call(&[]).unwrap(), 1.0);
+        assert_eq!(new_caller.call(&[]).unwrap(), 2.0);
+        assert_eq!(generator.lookup("g").unwrap().call(&[]).unwrap(), 2.0);
+    }
+
+    #[test]
+    fn reset() {
+        let mut generator = generator();
+        compile(&mut generator, "def f(x) x + 1").unwrap();
+        let old = compile(&mut generator, "def g(x) f(x) * 2").unwrap();
+        generator.reset();
+        assert!(matches!(generator.lookup("g"), Err(Error::UndefinedFunction(_))));
+        // Without the reset, `f` could not change its number of parameters.
+        let function = compile(&mut generator, "def f(x y) x * y").unwrap();
+        assert_eq!(function.call(&[2.0, 3.0]).unwrap(), 6.0);
+        assert_eq!(generator.kind_of("g"), None);
+        // The old code is leaked rather than freed.
+        assert_eq!(old.call(&[1.0]).unwrap(), 4.0);
+    }
+
+    #[test]
+    fn sizeof() {
+        assert_eq!(run("def f() sizeof(f64)", "f", &[]).unwrap(), 8.0);
+        assert_eq!(run("def f() sizeof(i32) * 2", "f", &[]).unwrap(), 8.0);
+        assert!(matches!(run("def f() sizeof(i64)", "f", &[]), Err(Error::UnknownType(ref name)) if name == "i64"));
+        assert!(matches!(run("def f() sizeof(1)", "f", &[]), Err(Error::Unexpected(_))));
+        assert!(matches!(run("def f() sizeof(f64, i32)", "f", &[]), Err(Error::WrongArgumentCount)));
+    }
+
+    #[test]
+    fn special_values() {
+        let eval = |source: &str| run(&format!("def f() {}", source), "f", &[]).unwrap();
+        assert_eq!(eval("inf + 1"), f64::INFINITY);
+        assert_eq!(eval("0 - inf"), f64::NEG_INFINITY);
+        assert!(eval("inf * 0").is_nan());
+        assert!(eval("nan + 1").is_nan());
+        assert_eq!(eval("1 < inf"), 1.0);
+        assert_eq!(eval("0 - inf < 0"), 1.0);
+        // `nan` is unordered, so every comparison with it is false, even with itself.
+        assert_eq!(eval("nan < 1"), 0.0);
+        assert_eq!(eval("1 < nan"), 0.0);
+        assert_eq!(eval("nan < nan"), 0.0);
+    }
+
+    /// The language has no conditional, so every function is a single block.
+    #[test]
+    fn stats() {
+        let mut generator = generator();
+        compile(&mut generator, "def add(x y) x + y").unwrap();
+        compile(&mut generator, "def poly(x) x * x * x + 2 * x * x + 1").unwrap();
+        let add = generator.stats("add").unwrap();
+        assert_eq!((add.blocks, add.instructions), (1, 2));
+        let poly = generator.stats("poly").unwrap();
+        assert_eq!(poly.blocks, 1);
+        assert!(poly.instructions > add.instructions);
+        assert!(generator.stats("unknown").is_none());
+    }
+
+    #[test]
+    fn verify() {
+        let mut generator = generator();
+        generator.set_verify(true);
+        compile(&mut generator, "def f(x) x * 2 < 3").unwrap();
+        // A function whose block does not end with a terminator.
+        let mut function = ir::Function::new();
+        let ebb = function.dfg.make_ebb();
+        let mut cursor = FuncCursor::new(&mut function);
+        cursor.insert_ebb(ebb);
+        cursor.ins().f64const(1.0);
+        assert!(matches!(generator.verify(&function), Err(Error::Verifier(_))));
+        generator.set_verify(false);
+        assert!(generator.verify(&function).is_ok());
+    }
+}
@@ -1,23 +1,34 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
 use std::io::{
     Bytes,
     Read,
 };
 use std::iter::Peekable;
 
+use crate::ast::Name;
 use crate::error::Result;
-use crate::error::Error::UnknownChar;
+use crate::error::Error::{MalformedNumber, NumberOutOfRange, UnknownChar, UnterminatedString};
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Token {
     Eof,
 
     // Commands.
     Def,
     Extern,
+    Include,
+    Inline,
+
+    // Keywords.
+    In,
 
     // Primary.
-    Identifier(String),
+    Identifier(Name),
     Number(f64),
+    /// Positional parameter `$N`.
+    Positional(usize),
+    String(String),
 
     // Operators.
     LessThan,
@@ -26,46 +37,195 @@ pub enum Token {
     Star,
 
     // Other.
+    Colon,
+    /// Text of a `##` comment, only lexed when doc comments are enabled.
+    DocComment(String),
+    Equal,
     SemiColon,
     OpenParen,
     CloseParen,
+    OpenBrace,
+    CloseBrace,
     Comma,
 }
 
+impl Token {
+    /// Get the category of the token, to highlight it.
+    pub fn category(&self) -> TokenCategory {
+        match *self {
+            Token::Def | Token::Extern | Token::Include | Token::Inline | Token::In => TokenCategory::Keyword,
+            Token::Identifier(_) | Token::Positional(_) => TokenCategory::Identifier,
+            Token::Number(_) => TokenCategory::Number,
+            Token::String(_) => TokenCategory::String,
+            Token::LessThan | Token::Minus | Token::Plus | Token::Star => TokenCategory::Operator,
+            Token::DocComment(_) => TokenCategory::Comment,
+            Token::Eof | Token::Colon | Token::Equal | Token::SemiColon | Token::OpenParen | Token::CloseParen | Token::OpenBrace |
+                Token::CloseBrace | Token::Comma => TokenCategory::Punctuation,
+        }
+    }
+}
+
+impl Display for Token {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        let spelling =
+            match *self {
+                Token::Eof => return write!(formatter, "end of input"),
+                Token::Def => "def",
+                Token::Extern => "extern",
+                Token::Include => "include",
+                Token::Inline => "inline",
+                Token::In => "in",
+                Token::Identifier(ref name) => return write!(formatter, "identifier `{}`", name),
+                Token::Number(number) => return write!(formatter, "number `{}`", number),
+                Token::Positional(index) => return write!(formatter, "`${}`", index),
+                Token::String(ref string) => return write!(formatter, "string `\"{}\"`", string),
+                Token::LessThan => "<",
+                Token::Minus => "-",
+                Token::Plus => "+",
+                Token::Star => "*",
+                Token::Colon => ":",
+                Token::DocComment(_) => return write!(formatter, "doc comment"),
+                Token::Equal => "=",
+                Token::SemiColon => ";",
+                Token::OpenParen => "(",
+                Token::CloseParen => ")",
+                Token::OpenBrace => "{",
+                Token::CloseBrace => "}",
+                Token::Comma => ",",
+            };
+        write!(formatter, "`{}`", spelling)
+    }
+}
+
+/// Category of a token, like the groups of the `Token` variants.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenCategory {
+    Comment,
+    Identifier,
+    Keyword,
+    Number,
+    Operator,
+    Punctuation,
+    String,
+}
+
+/// Spelling of the keywords, which can be changed to translate or experiment with the language.
+pub struct KeywordTable {
+    keywords: HashMap<String, Token>,
+}
+
+impl KeywordTable {
+    /// Create a table without any keyword.
+    pub fn empty() -> Self {
+        Self {
+            keywords: HashMap::new(),
+        }
+    }
+
+    fn get(&self, spelling: &str) -> Option<&Token> {
+        self.keywords.get(spelling)
+    }
+
+    /// Make `spelling`, which must be an identifier, produce `token`.
+    pub fn insert(&mut self, spelling: &str, token: Token) {
+        self.keywords.insert(spelling.to_string(), token);
+    }
+}
+
+impl Default for KeywordTable {
+    fn default() -> Self {
+        let mut table = Self::empty();
+        table.insert("def", Token::Def);
+        table.insert("extern", Token::Extern);
+        table.insert("in", Token::In);
+        table.insert("include", Token::Include);
+        table.insert("inline", Token::Inline);
+        table
+    }
+}
+
 pub struct Lexer<R: Read> {
     bytes: Peekable<Bytes<R>>,
+    /// Spelling of the identifier being lexed, reused to avoid allocating for known names.
+    buffer: String,
+    /// Line of the next char, counted from 1.
+    current_line: usize,
+    /// Whether `##` starts a doc comment token instead of a plain comment.
+    doc_comments: bool,
+    keywords: KeywordTable,
+    /// Line of the last token lexed.
+    line: usize,
     lookahead: Option<Token>,
+    /// Every identifier lexed so far.
+    names: HashSet<Name>,
 }
 
 impl<R: Read> Lexer<R> {
     pub fn new(reader: R) -> Self {
+        Self::with_keywords(reader, KeywordTable::default())
+    }
+
+    pub fn with_keywords(reader: R, keywords: KeywordTable) -> Self {
         Self {
             bytes: reader.bytes().peekable(),
+            buffer: String::new(),
+            current_line: 1,
+            doc_comments: true,
+            keywords,
+            line: 1,
             lookahead: None,
+            names: HashSet::new(),
         }
     }
 
-    fn comment(&mut self) -> Result<Token> {
+    /// Tell whether the rest of the line is blank or a comment, without reading past its end.
+    ///
+    /// Unlike `peek`, this does not wait for the next line, which the REPL has not read yet.
+    pub fn at_end_of_line(&mut self) -> Result<bool> {
+        if self.lookahead.is_some() {
+            return Ok(false);
+        }
         loop {
-            if let Some(char) = self.peek_char()? {
-                self.bytes.next();
-                if char == '\n' {
-                    break;
-                }
+            match self.peek_char()? {
+                Some(' ') | Some('\r') | Some('\t') => self.consume(),
+                Some('\n') | Some('#') | None => return Ok(true),
+                Some(_) => return Ok(false),
             }
-            else {
-                return Ok(Token::Eof);
+        }
+    }
+
+    fn comment(&mut self) -> Result<Token> {
+        self.consume(); // Eat `#`.
+        let is_doc = self.peek_char()? == Some('#');
+        let mut text = vec![];
+        while let Some(char) = self.peek_char()? {
+            self.consume();
+            if char == '\n' {
+                break;
             }
+            text.push(char as u8);
+        }
+        if is_doc && self.doc_comments {
+            // Skip the second `#`.
+            let text = String::from_utf8_lossy(&text[1..]);
+            return Ok(Token::DocComment(text.trim().to_string()));
         }
         self.next_token()
     }
 
+    /// Skip the current char, counting the lines.
+    fn consume(&mut self) {
+        if let Some(Ok(b'\n')) = self.bytes.next() {
+            self.current_line += 1;
+        }
+    }
+
     fn digits(&mut self) -> Result<String> {
         let mut buffer = String::new();
         loop {
             if let Some(char) = self.peek_char()? {
-                if char.is_numeric() {
-                    self.bytes.next();
+                if char.is_ascii_digit() {
+                    self.consume();
                     buffer.push(char);
                     continue;
                 }
@@ -76,52 +236,116 @@ impl<R: Read> Lexer<R> {
         Ok(buffer)
     }
 
+    /// Lex an identifier, a keyword or one of the special number literals `inf` and `nan`.
+    ///
+    /// Identifiers can contain `_` and any Unicode letter or digit, like `définir` or `my_var`.
+    ///
+    /// Like any NaN, `nan` is unordered: `nan < x` and `x < nan` are false for every `x`.
+    /// There is no negative literal: negative infinity is written `0 - inf`.
     fn identifier(&mut self) -> Result<Token> {
-        let mut ident = String::new();
+        self.buffer.clear();
         loop {
-            if let Some(char) = self.peek_char()? {
-                if char.is_ascii_alphanumeric() {
-                    self.bytes.next();
-                    ident.push(char);
-                    continue;
-                }
+            match self.peek_char()? {
+                Some(char) if char.is_ascii_alphanumeric() || char == '_' => {
+                    self.consume();
+                    self.buffer.push(char);
+                },
+                Some(char) if !char.is_ascii() => {
+                    let char = self.utf8_char()?;
+                    if !char.is_alphanumeric() {
+                        return Err(UnknownChar(char));
+                    }
+                    self.buffer.push(char);
+                },
+                _ => break,
             }
-            break;
         }
         let token =
-            match ident.as_str() {
-                "def" => Token::Def,
-                "extern" => Token::Extern,
-                _ => Token::Identifier(ident),
+            match self.keywords.get(&self.buffer) {
+                Some(token) => token.clone(),
+                None =>
+                    match self.buffer.as_str() {
+                        "inf" => Token::Number(f64::INFINITY),
+                        "nan" => Token::Number(f64::NAN),
+                        _ => Token::Identifier(self.intern()),
+                    },
             };
         Ok(token)
     }
 
+    /// Get the shared string for the identifier in `buffer`, only allocating the first time.
+    fn intern(&mut self) -> Name {
+        match self.names.get(self.buffer.as_str()) {
+            Some(name) => name.clone(),
+            None => {
+                let name: Name = self.buffer.as_str().into();
+                self.names.insert(name.clone());
+                name
+            },
+        }
+    }
+
+    /// Make `##` start a doc comment token, which is the default, or a plain comment.
+    pub fn set_doc_comments(&mut self, enabled: bool) {
+        self.doc_comments = enabled;
+    }
+
+    /// Line of the last token lexed, which is the line of the lookahead token after a `peek`.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Skip a `\` ending a line.
+    ///
+    /// Since tokens are read on demand, an incomplete expression already continues on the next
+    /// line: this only makes the continuation explicit.
+    fn line_continuation(&mut self) -> Result<Token> {
+        self.consume();
+        if let Some('\r') = self.peek_char()? {
+            self.consume();
+        }
+        match self.peek_char()? {
+            Some('\n') => {
+                self.consume();
+                self.next_token()
+            },
+            _ => Err(UnknownChar('\\')),
+        }
+    }
+
     pub fn next_token(&mut self) -> Result<Token> {
         if let Some(lookahead) = self.lookahead.take() {
             return Ok(lookahead);
         }
+        self.line = self.current_line;
         if let Some(&Ok(byte)) = self.bytes.peek() {
             return match byte {
                 b' ' | b'\n' | b'\r' | b'\t' => {
-                    self.bytes.next();
+                    self.consume();
                     self.next_token()
                 },
-                b'a' ..= b'z' | b'A' ..= b'Z' => self.identifier(),
+                b'a' ..= b'z' | b'A' ..= b'Z' | b'_' | 0x80 ..= 0xFF => self.identifier(),
                 b'0' ..= b'9' | b'.' => self.number(),
                 b'#' => self.comment(),
+                b'$' => self.positional(),
+                b'"' => self.string(),
+                b'\\' => self.line_continuation(),
                 _ => {
-                    self.bytes.next();
+                    self.consume();
                     let token =
                         match byte {
                             b'<' => Token::LessThan,
                             b'+' => Token::Plus,
                             b'-' => Token::Minus,
                             b'*' => Token::Star,
+                            b':' => Token::Colon,
+                            b'=' => Token::Equal,
                             b';' => Token::SemiColon,
                             b',' => Token::Comma,
                             b'(' => Token::OpenParen,
                             b')' => Token::CloseParen,
+                            b'{' => Token::OpenBrace,
+                            b'}' => Token::CloseBrace,
                             _ => return Err(UnknownChar(byte as char)),
                         };
                     Ok(token)
@@ -138,14 +362,39 @@ impl<R: Read> Lexer<R> {
 
     fn number(&mut self) -> Result<Token> {
         let integral = self.digits()?;
-        if let Some('.') = self.peek_char()? {
-            self.bytes.next();
-            let decimals = self.digits()?;
-             Ok(Token::Number(format!("{}.{}", integral, decimals).parse()?))
+        let literal =
+            if let Some('.') = self.peek_char()? {
+                self.consume();
+                let decimals = self.digits()?;
+                if integral.is_empty() && decimals.is_empty() {
+                    // A lone `.` is not a number.
+                    return Err(UnknownChar('.'));
+                }
+                format!("{}.{}", integral, decimals)
+            }
+            else {
+                integral
+            };
+        if let Some(char) = self.peek_char()? {
+            if char.is_ascii_alphabetic() || char == '_' {
+                // Report the whole word instead of splitting it into a number and an identifier.
+                let mut literal = literal;
+                while let Some(char) = self.peek_char()? {
+                    if !char.is_ascii_alphanumeric() && char != '_' && char != '.' {
+                        break;
+                    }
+                    self.consume();
+                    literal.push(char);
+                }
+                return Err(MalformedNumber(literal));
+            }
         }
-        else {
-            Ok(Token::Number(integral.parse()?))
+        let number: f64 = literal.parse()?;
+        // Parsing a literal too big for a f64 silently gives infinity.
+        if !number.is_finite() {
+            return Err(NumberOutOfRange(literal));
         }
+        Ok(Token::Number(number))
     }
 
     pub fn peek(&mut self) -> Result<&Token> {
@@ -169,4 +418,195 @@ impl<R: Read> Lexer<R> {
             None => Ok(None),
         }
     }
+
+    /// Lex a positional parameter like `$1`.
+    fn positional(&mut self) -> Result<Token> {
+        self.consume(); // Eat `$`.
+        let digits = self.digits()?;
+        match digits.parse() {
+            Ok(index) => Ok(Token::Positional(index)),
+            Err(_) => Err(UnknownChar('$')),
+        }
+    }
+
+    /// Lex a string literal, which has no escape sequences.
+    fn string(&mut self) -> Result<Token> {
+        self.consume(); // Eat `"`.
+        let mut bytes = vec![];
+        loop {
+            match self.peek_char()? {
+                Some('"') => {
+                    self.consume();
+                    break;
+                },
+                Some(char) => {
+                    self.consume();
+                    bytes.push(char as u8);
+                },
+                None => return Err(UnterminatedString),
+            }
+        }
+        Ok(Token::String(String::from_utf8_lossy(&bytes).to_string()))
+    }
+
+    /// Consume a non-ASCII char, encoded in UTF-8 as a leading byte followed by continuation
+    /// bytes.
+    fn utf8_char(&mut self) -> Result<char> {
+        let mut bytes = vec![];
+        while let Some(char) = self.peek_char()? {
+            let byte = char as u8;
+            let is_continuation = byte & 0xC0 == 0x80;
+            if !bytes.is_empty() && !is_continuation {
+                break;
+            }
+            self.consume();
+            bytes.push(byte);
+            if is_continuation && bytes.len() == 1 {
+                break;
+            }
+        }
+        match String::from_utf8(bytes) {
+            Ok(string) => Ok(string.chars().next().expect("char")),
+            Err(_) => Err(UnknownChar(char::REPLACEMENT_CHARACTER)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::error::Result;
+
+    use super::{KeywordTable, Lexer, Token, TokenCategory};
+
+    /// Lex `source` up to the end of input, stopping at the first error.
+    fn tokens(source: &str) -> Result<Vec<Token>> {
+        let mut lexer = Lexer::new(source.as_bytes());
+        let mut tokens = vec![];
+        loop {
+            match lexer.next_token()? {
+                Token::Eof => return Ok(tokens),
+                token => tokens.push(token),
+            }
+        }
+    }
+
+    #[test]
+    fn keyword_table() {
+        let mut keywords = KeywordTable::empty();
+        keywords.insert("définir", Token::Def);
+        keywords.insert("fonction", Token::Extern);
+        let mut lexer = Lexer::with_keywords("définir carré(x) def".as_bytes(), keywords);
+        assert_eq!(lexer.next_token().unwrap(), Token::Def);
+        assert_eq!(lexer.next_token().unwrap(), Token::Identifier("carré".into()));
+        assert_eq!(lexer.next_token().unwrap(), Token::OpenParen);
+        lexer.next_token().unwrap();
+        lexer.next_token().unwrap();
+        // `def` is not a keyword anymore.
+        assert_eq!(lexer.next_token().unwrap(), Token::Identifier("def".into()));
+    }
+
+    #[test]
+    fn line_continuation() {
+        let expected = vec![Token::Number(1.0), Token::Plus, Token::Number(2.0)];
+        assert_eq!(tokens("1 + \\\n2").unwrap(), expected);
+        assert_eq!(tokens("1 + \\\r\n2").unwrap(), expected);
+        assert!(matches!(tokens("1 \\ 2"), Err(Error::UnknownChar('\\'))));
+    }
+
+    #[test]
+    fn lone_dot() {
+        assert!(matches!(tokens("."), Err(Error::UnknownChar('.'))));
+        assert!(matches!(tokens("a.b"), Err(Error::UnknownChar('.'))));
+        assert_eq!(tokens(".5").unwrap(), vec![Token::Number(0.5)]);
+        assert_eq!(tokens("5.").unwrap(), vec![Token::Number(5.0)]);
+    }
+
+    /// Only the value of a literal is kept, and there are no radix prefixes nor separators.
+    #[test]
+    fn normalized_numbers() {
+        assert_eq!(tokens("1.50 001 .5").unwrap(), vec![Token::Number(1.5), Token::Number(1.0), Token::Number(0.5)]);
+        assert!(matches!(tokens("0xff"), Err(Error::MalformedNumber(ref literal)) if literal == "0xff"));
+        assert!(matches!(tokens("1_000"), Err(Error::MalformedNumber(ref literal)) if literal == "1_000"));
+    }
+
+    #[test]
+    fn number_followed_by_identifier() {
+        assert!(matches!(tokens("3abc"), Err(Error::MalformedNumber(ref literal)) if literal == "3abc"));
+        assert!(matches!(tokens("1.5x_2 + 1"), Err(Error::MalformedNumber(ref literal)) if literal == "1.5x_2"));
+        assert!(matches!(tokens("2_"), Err(Error::MalformedNumber(ref literal)) if literal == "2_"));
+        assert_eq!(tokens("3 abc").unwrap(), vec![Token::Number(3.0), Token::Identifier("abc".into())]);
+        assert_eq!(tokens("abc3").unwrap(), vec![Token::Identifier("abc3".into())]);
+    }
+
+    /// There is no exponent notation, so `1e400` is written with 400 zeros.
+    #[test]
+    fn number_out_of_range() {
+        let literal = format!("1{}", "0".repeat(400));
+        assert!(matches!(tokens(&literal), Err(Error::NumberOutOfRange(ref number)) if *number == literal));
+        let literal = format!("1{}", "0".repeat(308));
+        assert_eq!(tokens(&literal).unwrap(), vec![Token::Number(1e308)]);
+        assert!(matches!(tokens("1e400"), Err(Error::MalformedNumber(_))));
+    }
+
+    #[test]
+    fn positional() {
+        assert_eq!(tokens("$1 * $12").unwrap(), vec![Token::Positional(1), Token::Star, Token::Positional(12)]);
+        assert!(matches!(tokens("$x"), Err(Error::UnknownChar('$'))));
+    }
+
+    #[test]
+    fn special_numbers() {
+        let tokens = tokens("inf nan infinity").unwrap();
+        assert_eq!(tokens[0], Token::Number(f64::INFINITY));
+        assert!(matches!(tokens[1], Token::Number(number) if number.is_nan()));
+        assert_eq!(tokens[2], Token::Identifier("infinity".into()));
+    }
+
+    #[test]
+    fn token_category() {
+        let categories = [
+            (Token::Eof, TokenCategory::Punctuation),
+            (Token::Def, TokenCategory::Keyword),
+            (Token::Extern, TokenCategory::Keyword),
+            (Token::Include, TokenCategory::Keyword),
+            (Token::Inline, TokenCategory::Keyword),
+            (Token::In, TokenCategory::Keyword),
+            (Token::Identifier("x".into()), TokenCategory::Identifier),
+            (Token::Number(1.0), TokenCategory::Number),
+            (Token::Positional(1), TokenCategory::Identifier),
+            (Token::String("a".to_string()), TokenCategory::String),
+            (Token::LessThan, TokenCategory::Operator),
+            (Token::Minus, TokenCategory::Operator),
+            (Token::Plus, TokenCategory::Operator),
+            (Token::Star, TokenCategory::Operator),
+            (Token::Colon, TokenCategory::Punctuation),
+            (Token::DocComment("a".to_string()), TokenCategory::Comment),
+            (Token::Equal, TokenCategory::Punctuation),
+            (Token::SemiColon, TokenCategory::Punctuation),
+            (Token::OpenParen, TokenCategory::Punctuation),
+            (Token::CloseParen, TokenCategory::Punctuation),
+            (Token::OpenBrace, TokenCategory::Punctuation),
+            (Token::CloseBrace, TokenCategory::Punctuation),
+            (Token::Comma, TokenCategory::Punctuation),
+        ];
+        for (token, category) in &categories {
+            assert_eq!(token.category(), *category, "{:?}", token);
+        }
+    }
+
+    #[test]
+    fn underscore() {
+        let names = ["my_var", "_", "_x", "x_"];
+        let expected: Vec<_> = names.iter().map(|&name| Token::Identifier(name.into())).collect();
+        assert_eq!(tokens("my_var _ _x x_").unwrap(), expected);
+        assert_eq!(tokens("_*2").unwrap(), vec![Token::Identifier("_".into()), Token::Star, Token::Number(2.0)]);
+    }
+
+    #[test]
+    fn unicode_identifiers() {
+        assert_eq!(tokens("π2 日本").unwrap(), vec![Token::Identifier("π2".into()), Token::Identifier("日本".into())]);
+        assert!(matches!(tokens("x → y"), Err(Error::UnknownChar('→'))));
+        assert!(matches!(tokens("x€"), Err(Error::UnknownChar('€'))));
+    }
 }
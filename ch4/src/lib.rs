@@ -0,0 +1,11 @@
+pub mod ast;
+pub mod cache;
+pub mod error;
+pub mod gen;
+pub mod lexer;
+pub mod lint;
+pub mod parser;
+pub mod runtime;
+pub mod visit;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
@@ -0,0 +1,128 @@
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+
+use crate::ast::{Expr, Function, Name};
+use crate::visit::{self, Visitor};
+
+/// Code that compiles but is likely a mistake.
+#[derive(Debug)]
+pub enum Warning {
+    /// Parameter and name of a function whose body does not use the parameter.
+    UnusedParameter(Name, Name),
+}
+
+impl Display for Warning {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        match *self {
+            Warning::UnusedParameter(ref parameter, ref function) =>
+                write!(formatter, "unused parameter `{}` in `{}`", parameter, function),
+        }
+    }
+}
+
+/// Default values completing a call to the function named by the first argument with the number
+/// of arguments given by the second one.
+///
+/// The caller evaluates them in its scope, so the variables they use are used by the caller.
+pub type Defaults<'a> = dyn Fn(&str, usize) -> Vec<Expr> + 'a;
+
+/// Run every check on `function`, which calls functions with the `defaults`.
+pub fn check(function: &Function, defaults: &Defaults) -> Vec<Warning> {
+    unused_parameters(function, defaults).into_iter()
+        .map(|parameter| Warning::UnusedParameter(parameter, function.prototype.function_name.clone()))
+        .collect()
+}
+
+/// Get the parameters of `function` that its body never refers to, in declaration order.
+///
+/// A parameter is only used by a nested function if this one does not redeclare its name. It is
+/// also used by the calls whose missing arguments are `defaults` referring to it.
+pub fn unused_parameters(function: &Function, defaults: &Defaults) -> Vec<Name> {
+    let mut references = References {
+        defaults,
+        names: HashSet::new(),
+        shadowed: vec![],
+    };
+    visit::walk_function(&mut references, function);
+    function.prototype.parameters.iter()
+        .filter(|&parameter| !references.names.contains(parameter))
+        .cloned()
+        .collect()
+}
+
+/// Collect the names of the variables referenced from the outermost function.
+struct References<'a, 'b> {
+    defaults: &'a Defaults<'b>,
+    names: HashSet<Name>,
+    /// Parameters of the nested functions being visited.
+    shadowed: Vec<Name>,
+}
+
+impl<'a, 'b> Visitor for References<'a, 'b> {
+    fn visit_expr(&mut self, expr: &Expr) {
+        match *expr {
+            Expr::Call(ref name, ref args) => {
+                for default in (self.defaults)(name, args.len()) {
+                    self.visit_expr(&default);
+                }
+                visit::walk_expr(self, expr);
+            },
+            Expr::Variable(ref name) if !self.shadowed.contains(name) => {
+                self.names.insert(name.clone());
+            },
+            _ => visit::walk_expr(self, expr),
+        }
+    }
+
+    fn visit_function(&mut self, function: &Function) {
+        let len = self.shadowed.len();
+        self.shadowed.extend(function.prototype.parameters.iter().cloned());
+        visit::walk_function(self, function);
+        self.shadowed.truncate(len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::{Expr, Function};
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    use super::{check, unused_parameters};
+
+    fn definition(source: &str) -> Function {
+        Parser::new(Lexer::new(source.as_bytes())).definition().unwrap()
+    }
+
+    /// Defaults of `def f(x y = a)`.
+    fn defaults(name: &str, args: usize) -> Vec<Expr> {
+        if name == "f" && args == 1 { vec![Expr::variable("a")] } else { vec![] }
+    }
+
+    fn unused(source: &str) -> Vec<String> {
+        unused_parameters(&definition(source), &defaults).iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn check_message() {
+        let warnings = check(&definition("def g(x y) x"), &defaults);
+        let messages: Vec<_> = warnings.iter().map(ToString::to_string).collect();
+        assert_eq!(messages, ["unused parameter `y` in `g`"]);
+        assert!(check(&definition("def g(x y) x + y"), &defaults).is_empty());
+    }
+
+    #[test]
+    fn unused_parameter() {
+        assert_eq!(unused("def f(x y) x"), ["y"]);
+        assert_eq!(unused("def f(x y z) y"), ["x", "z"]);
+        assert!(unused("def f(x) f(x)").is_empty());
+        assert!(unused("def g(x) def h(y) x + y in h(1)").is_empty());
+        // The nested function refers to its own `x`.
+        assert_eq!(unused("def g(x) def h(x) x in h(1)"), ["x"]);
+        // The caller evaluates the default values in its scope.
+        assert!(unused("def g(a) f(3)").is_empty());
+        assert_eq!(unused("def g(a) f(3, 4)"), ["a"]);
+        assert_eq!(unused("def g(a) def h(a) f(3) in h(1)"), ["a"]);
+        assert!(unused("def g(a) def h(x y = a) x + y in h(1)").is_empty());
+    }
+}
@@ -1,76 +1,500 @@
-mod ast;
-mod error;
-mod gen;
-mod lexer;
-mod parser;
+mod options;
 
-use std::io::{Write, stdin, stdout};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write, stdin, stdout};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Instant;
 
 use cranelift_module::Linkage;
 
-use error::Result;
-use gen::Generator;
-use lexer::{Lexer, Token};
-use parser::Parser;
+use ch4::ast::{BinaryOp, Function};
+use ch4::cache::ObjectCache;
+use ch4::error::Error::{
+    self,
+    DeniedWarning,
+    EntryWithParameters,
+    EvaluationPanicked,
+    Expected,
+    IncludeCycle,
+    TooManyErrors,
+    UndefinedFunction,
+    UnexpectedTopLevelExpr,
+    UnknownCommand,
+    WrongArgumentCount,
+};
+use ch4::error::{ErrorKind, Result};
+use ch4::gen::Generator;
+use ch4::lexer::{Lexer, Token, TokenCategory};
+use ch4::lint;
+use ch4::parser::Parser;
+use options::Options;
 
-#[no_mangle]
-pub extern "C" fn putchard(char: f64) -> f64 {
-    println!("{}", char as u8 as char);
-    0.0
+/// Call a compiled top-level expression, on a thread with a bigger stack if requested.
+///
+/// This lets deep recursions run, but overflowing the stack still aborts the process.
+fn eval(function: extern "C" fn() -> f64, options: &Options) -> Result<f64> {
+    match options.stack_size {
+        Some(stack_size) => {
+            let thread = thread::Builder::new()
+                .stack_size(stack_size)
+                .spawn(move || function())?;
+            thread.join().map_err(|_| EvaluationPanicked)
+        },
+        None => Ok(function()),
+    }
+}
+
+/// Maximum number of calls timed by `:bench`, to keep the REPL responsive.
+const MAX_BENCH_CALLS: f64 = 1e8;
+
+#[derive(Default)]
+struct Stats {
+    /// Functions defined with `def`, including redefinitions.
+    definitions: usize,
+    errors: usize,
+    /// Top-level expressions evaluated successfully.
+    expressions: usize,
+    externs: usize,
 }
 
 fn main() -> Result<()> {
-    let stdin = stdin();
-    let lexer = Lexer::new(stdin);
-    let mut parser = Parser::new(lexer);
+    let options = Options::from_args()?;
     let mut generator = Generator::new();
-    print!("ready> ");
-    stdout().flush()?;
+    generator.set_cache(options.cache);
+    for &(ref name, value) in &options.defines {
+        generator.define_constant(name, value);
+    }
+    if let Some(ref directory) = options.object_cache {
+        generator.set_object_cache(Some(ObjectCache::new(directory)?));
+    }
+    if let Some(ref path) = options.emit_ir_file {
+        generator.print_ir_to(Box::new(BufWriter::new(File::create(path)?)));
+    }
+    let mut stats = Stats::default();
+    if let Some(ref prelude) = options.prelude {
+        let file = BufReader::new(File::open(prelude)?);
+        let mut parser = new_parser(Lexer::new(file), &options);
+        let mut includes = vec![Path::new(prelude).canonicalize()?];
+        run(&mut parser, &mut generator, &options, &mut stats, false, &mut includes)?;
+    }
+
+    let input: Box<dyn Read> =
+        match options.input {
+            Some(ref path) => Box::new(BufReader::new(File::open(path)?)),
+            None => Box::new(stdin()),
+        };
+    let lexer = Lexer::new(input);
+    if options.dump_tokens {
+        return dump_tokens(lexer, options.color);
+    }
+
+    let mut includes =
+        match options.input {
+            Some(ref path) => vec![Path::new(path).canonicalize()?],
+            None => vec![],
+        };
+    let mut parser = new_parser(lexer, &options);
+    if options.parse_only {
+        parse(&mut parser, &options, &mut stats)?;
+        if stats.errors > 0 {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+    run(&mut parser, &mut generator, &options, &mut stats, options.input.is_none(), &mut includes)?;
+    if options.interactive && options.input.is_some() {
+        let mut parser = new_parser(Lexer::new(stdin()), &options);
+        run(&mut parser, &mut generator, &options, &mut stats, true, &mut vec![])?;
+    }
+    else if options.input.is_some() && stats.errors > 0 {
+        // Like a compiler, fail when a file has errors.
+        generator.flush_ir()?;
+        process::exit(1);
+    }
+    let exit_code =
+        match options.entry {
+            Some(ref entry) => {
+                let function = generator.lookup(entry)?;
+                let function = function.nullary().ok_or_else(|| EntryWithParameters(entry.clone(), function.arity()))?;
+                let result = eval(function, &options)?;
+                print_result(result, &options);
+                Some(exit_code(result))
+            },
+            None => None,
+        };
+    if options.summary {
+        println!("functions: {}, externs: {}, expressions: {}", stats.definitions, stats.externs, stats.expressions);
+        if let Some(cache) = generator.object_cache() {
+            println!("object cache: {} hits, {} misses", cache.hits(), cache.misses());
+        }
+    }
+    if let Some(exit_code) = exit_code {
+        generator.flush_ir()?;
+        process::exit(exit_code);
+    }
+    Ok(())
+}
+
+/// Print the warnings about `definition`, or turn the first one into an error with
+/// `--warnings-as-errors`.
+fn check(definition: &Function, generator: &Generator, line: usize, options: &Options) -> Result<()> {
+    for warning in lint::check(definition, &|name, args| generator.missing_defaults(name, args)) {
+        if options.warnings_as_errors {
+            return Err(DeniedWarning(warning.to_string()));
+        }
+        eprintln!("Warning at line {}: {}", line, warning);
+    }
+    Ok(())
+}
+
+/// Get the ANSI escape code of the color of the tokens of `category`.
+fn color_code(category: TokenCategory) -> u8 {
+    match category {
+        TokenCategory::Comment => 90,
+        TokenCategory::Identifier => 33,
+        TokenCategory::Keyword => 35,
+        TokenCategory::Number => 36,
+        TokenCategory::Operator => 31,
+        TokenCategory::Punctuation => 39,
+        TokenCategory::String => 32,
+    }
+}
+
+/// Run a REPL command, like `:type 1 + 2`.
+///
+/// `:apply $1 * $2, 3, 4` calls an expression using positional parameters with the numbers
+/// following it. `:bench 1 + 2 1000` compiles the expression once and times 1000 calls.
+/// `:prec` prints the precedence of the operators and `:prec + 50` changes it for the following
+/// expressions of the same input.
+fn command<R: Read>(parser: &mut Parser<R>, generator: &mut Generator) -> Result<()> {
+    parser.advance()?; // Eat `:`.
+    let name =
+        match parser.advance()? {
+            Token::Identifier(name) => name,
+            token => return Err(Expected("a command name".to_string(), token)),
+        };
+    match &*name {
+        "apply" => {
+            let function = parser.toplevel().and_then(|expr| generator.function(expr))?;
+            let mut args = vec![];
+            while *parser.peek()? == Token::Comma {
+                parser.advance()?;
+                match parser.advance()? {
+                    Token::Number(number) => args.push(number),
+                    token => return Err(Expected("a number".to_string(), token)),
+                }
+            }
+            println!("{}", function.call(&args)?);
+        },
+        "bench" => {
+            let function = parser.toplevel()
+                .and_then(|expr| generator.function(expr))
+                .and_then(|function| function.nullary().ok_or(WrongArgumentCount))?;
+            let count =
+                match parser.advance()? {
+                    Token::Number(number) if number.fract() == 0.0 && (1.0..=MAX_BENCH_CALLS).contains(&number) =>
+                        number as u32,
+                    token => return Err(Expected(format!("a number of calls between 1 and {}", MAX_BENCH_CALLS), token)),
+                };
+            let start = Instant::now();
+            for _ in 0..count {
+                function();
+            }
+            let duration = start.elapsed();
+            println!("{} calls in {:?} ({:?} per call)", count, duration, duration / count);
+        },
+        "cfg" => {
+            let function_name =
+                match parser.advance()? {
+                    Token::Identifier(name) => name,
+                    token => return Err(Expected("a function name".to_string(), token)),
+                };
+            let dot = generator.cfg_dot(&function_name).ok_or_else(|| UndefinedFunction(function_name.to_string()))?;
+            print!("{}", dot);
+        },
+        "prec" => {
+            let op =
+                match *parser.peek()? {
+                    Token::LessThan => BinaryOp::LessThan,
+                    Token::Minus => BinaryOp::Minus,
+                    Token::Plus => BinaryOp::Plus,
+                    Token::Star => BinaryOp::Times,
+                    _ => {
+                        for (op, precedence) in parser.precedences() {
+                            println!("{} {}", op, precedence);
+                        }
+                        return Ok(());
+                    },
+                };
+            parser.advance()?;
+            let precedence =
+                match parser.advance()? {
+                    Token::Number(number) if number.fract() == 0.0 && (1.0..=1000.0).contains(&number) =>
+                        number as i32,
+                    token => return Err(Expected("a precedence between 1 and 1000".to_string(), token)),
+                };
+            parser.set_precedence(op, precedence);
+        },
+        "reset" => {
+            generator.reset();
+            println!("All definitions were removed");
+        },
+        "stats" => {
+            let function_name =
+                match parser.advance()? {
+                    Token::Identifier(name) => name,
+                    token => return Err(Expected("a function name".to_string(), token)),
+                };
+            let stats = generator.stats(&function_name).ok_or_else(|| UndefinedFunction(function_name.to_string()))?;
+            println!("blocks: {}, instructions: {}", stats.blocks, stats.instructions);
+        },
+        "type" => {
+            let expr = parser.expr()?;
+            println!("{}", expr.infer_type());
+        },
+        _ => return Err(UnknownCommand(name.to_string())),
+    }
+    Ok(())
+}
+
+fn dump_tokens<R: Read>(mut lexer: Lexer<R>, color: bool) -> Result<()> {
+    loop {
+        match lexer.next_token() {
+            Ok(token) => {
+                if color {
+                    println!("\x1b[{}m{:?}\x1b[0m", color_code(token.category()), token);
+                }
+                else {
+                    println!("{:?}", token);
+                }
+                if token == Token::Eof {
+                    break;
+                }
+            },
+            Err(error) => eprintln!("Error: {:?}", error),
+        }
+    }
+    Ok(())
+}
+
+/// Convert the result of the entry point to an exit code, like the `int` returned by `main` in C.
+///
+/// The result is truncated towards zero, saturating at the bounds of `i32`, with NaN giving 0. The
+/// system then only keeps the low 8 bits on Unix, so `256` exits with 0 and `-1` with 255.
+fn exit_code(result: f64) -> i32 {
+    result as i32
+}
+
+/// Create a parser reading from `lexer`, configured by the command-line options.
+fn new_parser<R: Read>(lexer: Lexer<R>, options: &Options) -> Parser<R> {
+    let mut parser = Parser::new(lexer);
+    parser.set_strict_semicolons(options.strict_semicolons);
+    parser
+}
+
+/// Open the file included by `include "path"`, where `path` is relative to the including file.
+///
+/// `includes` holds the files being run, from the outermost one, to detect cycles.
+fn open_include(path: &str, includes: &[PathBuf]) -> Result<(PathBuf, BufReader<File>)> {
+    let path =
+        match includes.last().and_then(|file| file.parent()) {
+            Some(directory) => directory.join(path),
+            None => PathBuf::from(path),
+        };
+    let path = path.canonicalize()?;
+    if includes.contains(&path) {
+        return Err(IncludeCycle(path.display().to_string()));
+    }
+    let file = BufReader::new(File::open(&path)?);
+    Ok((path, file))
+}
+
+/// Print the AST of every item read by `parser`, without compiling them.
+fn parse<R: Read>(parser: &mut Parser<R>, options: &Options, stats: &mut Stats) -> Result<()> {
+    loop {
+        let result =
+            match *parser.peek()? {
+                Token::Eof => break,
+                Token::SemiColon => parser.advance().map(|_| ()),
+                Token::DocComment(_) => parser.doc_comment(),
+                Token::Def | Token::Inline => parser.definition().map(|definition| println!("{}", definition)),
+                Token::Extern => parser.extern_().map(|prototypes| {
+                    for prototype in prototypes {
+                        println!("extern {}", prototype);
+                    }
+                }),
+                Token::Include => parser.include().map(|path| println!("include {:?}", path)),
+                _ => parser.toplevel().map(|expr| println!("{}", expr.body)),
+            };
+        if let Err(error) = result {
+            report(error, parser.line(), options, stats)?;
+            parser.recover()?;
+        }
+    }
+    Ok(())
+}
+
+/// Print an error found at `line`, prefixed by the stage that produced it, aborting once the
+/// maximum number of errors is reached.
+fn report(error: Error, line: usize, options: &Options, stats: &mut Stats) -> Result<()> {
+    eprintln!("{} error at line {}: {:?}", stage(error.kind()), line, error);
+    stats.errors += 1;
+    match options.max_errors {
+        Some(max_errors) if stats.errors >= max_errors.get() => Err(TooManyErrors(stats.errors)),
+        _ => Ok(()),
+    }
+}
+
+/// Get the line to report `error` at: the line `current` of the token where parsing failed, or else
+/// the line `start` of the item, since the parser may have looked at the next item.
+fn error_line(error: &Error, start: usize, current: usize) -> usize {
+    match error.kind() {
+        ErrorKind::Lex | ErrorKind::UnexpectedToken => current,
+        _ => start,
+    }
+}
+
+fn print_result(result: f64, options: &Options) {
+    if options.print_result_as_hex {
+        println!("{} ({:#018x})", result, result.to_bits());
+    }
+    else {
+        println!("{}", result);
+    }
+}
+
+fn prompt(interactive: bool) -> Result<()> {
+    if interactive {
+        print!("ready> ");
+        stdout().flush()?;
+    }
+    Ok(())
+}
+
+/// Compile and evaluate every item read by `parser`, prompting for each one in interactive mode.
+fn run<R: Read>(parser: &mut Parser<R>, generator: &mut Generator, options: &Options, stats: &mut Stats,
+    interactive: bool, includes: &mut Vec<PathBuf>) -> Result<()>
+{
+    prompt(interactive)?;
     loop {
         let token =
-            match parser.lexer.peek() {
-                Ok(ref token) => *token,
+            match parser.peek() {
+                Ok(token) => token.clone(),
                 Err(error) => {
-                    eprintln!("Error: {:?}", error);
+                    report(error, parser.line(), options, stats)?;
                     continue;
                 },
             };
+        // The parser can read past the end of an item, so this is its line.
+        let start = parser.line();
         match token {
             Token::Eof => break,
             Token::SemiColon => {
-                parser.lexer.next_token()?;
+                parser.advance()?;
                 continue;
             },
-            Token::Def => {
-                match parser.definition().and_then(|definition| generator.function(definition)) {
-                    Ok(_definition) => (),
+            Token::Colon => {
+                if let Err(error) = command(parser, generator) {
+                    let line = error_line(&error, start, parser.line());
+                    report(error, line, options, stats)?;
+                    parser.recover()?;
+                }
+            },
+            Token::DocComment(_) => {
+                if let Err(error) = parser.doc_comment() {
+                    report(error, parser.line(), options, stats)?;
+                }
+                continue;
+            },
+            Token::Def | Token::Inline => {
+                let result = parser.definition().and_then(|definition| {
+                    check(&definition, generator, start, options)?;
+                    generator.function(definition)
+                });
+                match result {
+                    Ok(_definition) => stats.definitions += 1,
                     Err(error) => {
-                        parser.lexer.next_token()?;
-                        eprintln!("Error: {:?}", error);
+                        let line = error_line(&error, start, parser.line());
+                        report(error, line, options, stats)?;
+                        parser.recover()?;
+                    },
+                }
+            },
+            Token::Include => {
+                match parser.include().and_then(|path| open_include(&path, includes)) {
+                    Ok((path, file)) => {
+                        let mut included = new_parser(Lexer::new(file), options);
+                        includes.push(path);
+                        let result = run(&mut included, generator, options, stats, false, includes);
+                        includes.pop();
+                        result?;
+                    },
+                    Err(error) => {
+                        let line = error_line(&error, start, parser.line());
+                        report(error, line, options, stats)?;
+                        parser.recover()?;
                     },
                 }
             },
             Token::Extern => {
-                match parser.extern_().and_then(|prototype| generator.prototype(&prototype, Linkage::Import)) {
-                    Ok(prototype) => println!("{}", prototype),
+                let result = parser.extern_().and_then(|prototypes| {
+                    for prototype in &prototypes {
+                        generator.prototype(prototype, Linkage::Import)?;
+                    }
+                    Ok(prototypes)
+                });
+                match result {
+                    Ok(prototypes) => {
+                        stats.externs += prototypes.len();
+                        for prototype in prototypes {
+                            println!("extern {}", prototype);
+                        }
+                    },
                     Err(error) => {
-                        parser.lexer.next_token()?;
-                        eprintln!("Error: {:?}", error);
+                        let line = error_line(&error, start, parser.line());
+                        report(error, line, options, stats)?;
+                        parser.recover()?;
                     },
                 }
             },
             _ => {
-                match parser.toplevel().and_then(|expr| generator.function(expr)) {
-                    Ok(function) => println!("{}", function()),
+                let result = parser.toplevel()
+                    .and_then(|expr| if options.strict { Err(UnexpectedTopLevelExpr) } else { Ok(expr) })
+                    .and_then(|expr| generator.function(expr))
+                    .and_then(|function| function.nullary().ok_or(WrongArgumentCount))
+                    .and_then(|function| eval(function, options));
+                match result {
+                    Ok(result) => {
+                        stats.expressions += 1;
+                        generator.set_last_result(result);
+                        if !options.discard_results {
+                            print_result(result, options);
+                        }
+                    },
                     Err(error) => {
-                        parser.lexer.next_token()?;
-                        eprintln!("Error: {:?}", error);
+                        let line = error_line(&error, start, parser.line());
+                        report(error, line, options, stats)?;
+                        parser.recover()?;
                     },
                 }
             },
         }
-        print!("ready> ");
-        stdout().flush()?;
+        prompt(interactive)?;
     }
     Ok(())
 }
+
+/// Name the stage producing the errors of `kind`, from the lexer to the evaluation.
+fn stage(kind: ErrorKind) -> &'static str {
+    match kind {
+        ErrorKind::Lex => "Lex",
+        ErrorKind::UnexpectedToken => "Parse",
+        ErrorKind::ArgCount | ErrorKind::Codegen | ErrorKind::Redefinition | ErrorKind::UndefinedFunction |
+            ErrorKind::UndefinedOperator | ErrorKind::UndefinedVariable => "Compile",
+        ErrorKind::Runtime => "Runtime",
+        ErrorKind::Io => "I/O",
+        ErrorKind::Usage => "Usage",
+    }
+}
@@ -0,0 +1,116 @@
+use std::env;
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+
+use ch4::error::Error::{InvalidOptionValue, MissingOptionValue, UnknownOption};
+use ch4::error::Result;
+
+pub struct Options {
+    /// Reuse the code of a function redefined with the same source.
+    pub cache: bool,
+    /// Color the tokens printed by `--dump-tokens` according to their category, unless the
+    /// `NO_COLOR` environment variable is set.
+    pub color: bool,
+    /// Constants given with `--define NAME=VALUE`.
+    pub defines: Vec<(String, f64)>,
+    /// Run top-level expressions for their side effects only, without printing their result.
+    pub discard_results: bool,
+    /// Print the tokens of the input instead of compiling it.
+    pub dump_tokens: bool,
+    /// File to write the IR to instead of the standard output.
+    pub emit_ir_file: Option<String>,
+    /// Function called after compiling the input, whose result is printed and becomes the exit
+    /// code.
+    pub entry: Option<String>,
+    /// File to read instead of the interactive standard input.
+    pub input: Option<String>,
+    /// Read the interactive standard input after the input file, with its definitions.
+    pub interactive: bool,
+    /// Abort after this number of errors, which cannot be 0.
+    pub max_errors: Option<NonZeroUsize>,
+    /// Directory where the compiled definitions are cached across runs.
+    pub object_cache: Option<String>,
+    /// Print the AST of the input instead of compiling it, exiting with an error status if it
+    /// does not parse.
+    pub parse_only: bool,
+    /// File compiled before reading the interactive input.
+    pub prelude: Option<String>,
+    /// Also print the bits of the results, to debug the generated floating-point code.
+    pub print_result_as_hex: bool,
+    /// Run top-level expressions on a dedicated thread with this stack size, in bytes.
+    pub stack_size: Option<usize>,
+    /// Reject the top-level expressions, to compile a library made only of declarations.
+    pub strict: bool,
+    /// Require `;` after the definitions and the externs.
+    pub strict_semicolons: bool,
+    /// Print the number of functions, externs and expressions compiled before exiting.
+    pub summary: bool,
+    /// Reject the definitions having warnings instead of compiling them.
+    pub warnings_as_errors: bool,
+}
+
+impl Options {
+    pub fn from_args() -> Result<Self> {
+        let mut options = Self {
+            cache: false,
+            color: false,
+            defines: vec![],
+            discard_results: false,
+            dump_tokens: false,
+            emit_ir_file: None,
+            entry: None,
+            input: None,
+            interactive: false,
+            max_errors: None,
+            object_cache: None,
+            parse_only: false,
+            prelude: None,
+            print_result_as_hex: false,
+            stack_size: None,
+            strict: false,
+            strict_semicolons: false,
+            summary: false,
+            warnings_as_errors: false,
+        };
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--cache" => options.cache = true,
+                "--color" => options.color = env::var_os("NO_COLOR").is_none(),
+                "--define" => options.defines.push(define(value(&mut args, &arg)?)?),
+                "--discard-results" => options.discard_results = true,
+                "--dump-tokens" => options.dump_tokens = true,
+                "--emit-ir-file" => options.emit_ir_file = Some(value(&mut args, &arg)?),
+                "--entry" => options.entry = Some(value(&mut args, &arg)?),
+                "-i" => options.interactive = true,
+                "--max-errors" => options.max_errors = Some(value(&mut args, &arg)?),
+                "--object-cache" => options.object_cache = Some(value(&mut args, &arg)?),
+                "--parse-only" => options.parse_only = true,
+                "--prelude" => options.prelude = Some(value(&mut args, &arg)?),
+                "--print-result-as-hex" => options.print_result_as_hex = true,
+                "--stack-size" => options.stack_size = Some(value(&mut args, &arg)?),
+                "--strict" => options.strict = true,
+                "--strict-semicolons" => options.strict_semicolons = true,
+                "--summary" => options.summary = true,
+                "--warnings-as-errors" => options.warnings_as_errors = true,
+                _ if !arg.starts_with('-') && options.input.is_none() => options.input = Some(arg),
+                _ => return Err(UnknownOption(arg)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Parse a `NAME=VALUE` definition.
+fn define(definition: String) -> Result<(String, f64)> {
+    let mut parts = definition.splitn(2, '=');
+    match (parts.next(), parts.next().map(str::parse)) {
+        (Some(name), Some(Ok(value))) if !name.is_empty() => Ok((name.to_string(), value)),
+        _ => Err(InvalidOptionValue("--define".to_string(), definition)),
+    }
+}
+
+fn value<T: FromStr>(args: &mut impl Iterator<Item=String>, option: &str) -> Result<T> {
+    let value = args.next().ok_or_else(|| MissingOptionValue(option.to_string()))?;
+    value.parse().map_err(|_| InvalidOptionValue(option.to_string(), value))
+}
@@ -1,23 +1,58 @@
 use std::collections::HashMap;
 use std::io::Read;
+use std::mem;
 
 use crate::ast::{
     BinaryOp,
     Expr,
     Function,
+    Name,
     Prototype,
+    Type,
+    positional_parameter,
+};
+use crate::error::{ErrorKind, Result};
+use crate::error::Error::{
+    DefaultUsesParameter,
+    DefinitionInDefault,
+    EmptyBody,
+    EmptyParens,
+    Expected,
+    RequiredAfterDefault,
+    UndefinedOperator,
+    Unexpected,
+    UnknownType,
 };
-use crate::error::Result;
-use crate::error::Error::{Undefined, Unexpected};
 use crate::lexer::{Lexer, Token};
 
+/// Names, types and default values of the parameters of a prototype.
+type Parameters = (Vec<Name>, Vec<Type>, Vec<Option<Expr>>);
+
+/// Parser producing one item at a time.
+///
+/// Parsing an item allocates only its AST, which is handed over to the caller. The only state that
+/// grows is the set of identifiers interned by the lexer, so a long session of redefinitions only
+/// grows with the number of distinct names, not with the number of items.
 pub struct Parser<R: Read> {
+    /// Fixed set of built-in operators, whose precedence can be changed with `set_precedence`.
     bin_precedence: HashMap<BinaryOp, i32>,
+    /// Documentation for the next prototype.
+    doc: Option<String>,
+    /// Counter used to name the anonymous top-level functions.
     index: usize,
-    pub lexer: Lexer<R>,
+    lexer: Lexer<R>,
+    /// Text of the `##` comments preceding the current token inside an item, which document the next
+    /// item if it starts with this token.
+    pending_doc: Vec<String>,
+    /// Whether the definitions and the externs must end with `;`.
+    strict_semicolons: bool,
 }
 
 impl<R: Read> Parser<R> {
+    /// Create a parser reading the tokens of `lexer`.
+    ///
+    /// The `##` comments are only doc comments where an item can start, as seen by `peek`:
+    /// elsewhere, like inside a definition, they are plain comments.
     pub fn new(lexer: Lexer<R>) -> Self {
         let mut bin_precedence = HashMap::new();
         bin_precedence.insert(BinaryOp::LessThan, 10);
@@ -26,18 +61,66 @@ impl<R: Read> Parser<R> {
         bin_precedence.insert(BinaryOp::Times, 40);
         Self {
             bin_precedence,
+            doc: None,
             index: 0,
             lexer,
+            pending_doc: vec![],
+            strict_semicolons: false,
         }
     }
 
+    /// Append a line to the documentation for the next prototype.
+    fn add_doc(&mut self, text: String) {
+        let doc =
+            match self.doc.take() {
+                Some(doc) => format!("{}\n{}", doc, text),
+                None => text,
+            };
+        self.doc = Some(doc);
+    }
+
+    /// Consume the current token.
+    pub fn advance(&mut self) -> Result<Token> {
+        self.next_token()
+    }
+
+    /// Skip the rest of an item after an error, up to the `;` ending it, without consuming the start
+    /// of the next item nor reading past the end of the line, which the REPL has not read yet.
+    pub fn recover(&mut self) -> Result<()> {
+        loop {
+            if self.lexer.at_end_of_line()? {
+                return Ok(());
+            }
+            match self.lexer.peek() {
+                Ok(&Token::SemiColon) => {
+                    self.lexer.next_token()?;
+                    return Ok(());
+                },
+                Ok(&Token::Colon) | Ok(&Token::Def) | Ok(&Token::DocComment(_)) | Ok(&Token::Eof) | Ok(&Token::Extern) |
+                    Ok(&Token::Include) | Ok(&Token::Inline) => return Ok(()),
+                Ok(_) => {
+                    self.lexer.next_token()?;
+                },
+                // The lexer skipped the invalid character.
+                Err(ref error) if error.kind() == ErrorKind::Lex => (),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Parse the arguments of a call, which can end with a trailing comma like `f(1, 2,)`.
     fn args(&mut self) -> Result<Vec<Expr>> {
-        if *self.lexer.peek()? == Token::CloseParen {
-            return Ok(vec![]);
+        match *self.peek_token()? {
+            Token::CloseParen => return Ok(vec![]),
+            Token::Comma => return Err(Unexpected("`,` before the first argument")),
+            _ => (),
         }
         let mut args = vec![self.expr()?];
-        while *self.lexer.peek()? == Token::Comma {
+        while *self.peek_token()? == Token::Comma {
             self.eat(Token::Comma)?;
+            if *self.peek_token()? == Token::CloseParen {
+                break;
+            }
             args.push(self.expr()?);
         }
         Ok(args)
@@ -45,7 +128,7 @@ impl<R: Read> Parser<R> {
 
     fn binary_op(&mut self) -> Result<Option<BinaryOp>> {
         let op =
-            match self.lexer.peek()? {
+            match self.peek_token()? {
                 Token::LessThan => BinaryOp::LessThan,
                 Token::Minus => BinaryOp::Minus,
                 Token::Plus => BinaryOp::Plus,
@@ -63,7 +146,7 @@ impl<R: Read> Parser<R> {
                     Ok(left)
                 }
                 else {
-                    self.lexer.next_token()?; // Eat binary operator.
+                    self.next_token()?; // Eat binary operator.
                     let right = self.primary()?;
                     let right =
                         match self.binary_op()? {
@@ -85,45 +168,110 @@ impl<R: Read> Parser<R> {
         }
     }
 
+    /// Parse a top-level definition, optionally preceded by `inline`.
     pub fn definition(&mut self) -> Result<Function> {
-        self.eat(Token::Def)?;
-        let prototype = self.prototype()?;
-        let body = self.expr()?;
-        Ok(Function {
-            body,
-            prototype,
-        })
+        let function = self.function()?;
+        self.end_of_item("definition")?;
+        Ok(function)
+    }
+
+    /// Parse a `##` comment documenting the next prototype.
+    pub fn doc_comment(&mut self) -> Result<()> {
+        match self.lexer.next_token()? {
+            Token::DocComment(text) => {
+                self.add_doc(text);
+                Ok(())
+            },
+            _ => Err(Unexpected("token, expecting a doc comment")),
+        }
     }
 
     fn eat(&mut self, token: Token) -> Result<()> {
-        let current_token = self.lexer.next_token()?;
+        let current_token = self.next_token()?;
         if current_token != token {
-            return Err(Unexpected("token"));
+            return Err(Expected(token.to_string(), current_token));
         }
         Ok(())
     }
 
-    fn expr(&mut self) -> Result<Expr> {
+    /// Check that an item is followed by `;` in strict mode, leaving the `;` to the caller.
+    fn end_of_item(&mut self, item: &str) -> Result<()> {
+        // Without strict semicolons, do not peek, which would wait for the next line in the REPL.
+        if !self.strict_semicolons {
+            return Ok(());
+        }
+        match *self.peek_token()? {
+            Token::SemiColon => Ok(()),
+            ref token => Err(Expected(format!("`;` after the {}", item), token.clone())),
+        }
+    }
+
+    pub fn expr(&mut self) -> Result<Expr> {
         let left = self.primary()?;
         self.binary_right(0, left)
     }
 
-    pub fn extern_(&mut self) -> Result<Prototype> {
+    /// Parse either a single extern declaration or a block of declarations like
+    /// `extern { sin(x); cos(x) }`.
+    pub fn extern_(&mut self) -> Result<Vec<Prototype>> {
         self.eat(Token::Extern)?;
-        self.prototype()
+        if *self.peek_token()? != Token::OpenBrace {
+            let prototype = self.prototype()?;
+            self.end_of_item("extern")?;
+            return Ok(vec![prototype]);
+        }
+
+        self.eat(Token::OpenBrace)?;
+        let mut prototypes = vec![];
+        loop {
+            match *self.peek()? {
+                Token::CloseBrace => {
+                    self.next_token()?;
+                    break;
+                },
+                Token::SemiColon => {
+                    self.next_token()?;
+                },
+                Token::DocComment(_) => self.doc_comment()?,
+                Token::Eof => return Err(Unexpected("end of input, expecting `}` to close the extern block")),
+                _ => prototypes.push(self.prototype()?),
+            }
+        }
+        self.end_of_item("extern block")?;
+        Ok(prototypes)
     }
 
-    fn ident(&mut self) -> Result<String> {
-        match self.lexer.next_token()? {
+    /// Parse a function definition, optionally preceded by `inline`, without its `;`.
+    fn function(&mut self) -> Result<Function> {
+        let inline = *self.peek_token()? == Token::Inline;
+        if inline {
+            self.eat(Token::Inline)?;
+        }
+        self.eat(Token::Def)?;
+        let mut prototype = self.prototype()?;
+        prototype.inline = inline;
+        match *self.peek_token()? {
+            Token::Eof | Token::SemiColon => return Err(EmptyBody(prototype.function_name.to_string())),
+            _ => (),
+        }
+        let body = self.expr()?;
+        Ok(Function {
+            body,
+            prototype,
+        })
+    }
+
+    fn ident(&mut self) -> Result<Name> {
+        match self.next_token()? {
             Token::Identifier(ident) => Ok(ident),
-            _ => Err(Unexpected("token, expecting identifier")),
+            token => Err(Expected("an identifier".to_string(), token)),
         }
     }
 
     fn ident_expr(&mut self) -> Result<Expr> {
         let name = self.ident()?;
         let ast =
-            match self.lexer.peek()? {
+            match self.peek_token()? {
                 Token::OpenParen => {
                     self.eat(Token::OpenParen)?;
                     let args = self.args()?;
@@ -135,69 +283,433 @@ impl<R: Read> Parser<R> {
         Ok(ast)
     }
 
-    fn parameters(&mut self) -> Result<Vec<String>> {
+    /// Parse `include "file"`, returning the path of the file.
+    pub fn include(&mut self) -> Result<String> {
+        self.eat(Token::Include)?;
+        match self.next_token()? {
+            Token::String(path) => Ok(path),
+            token => Err(Expected("the path of the file to include".to_string(), token)),
+        }
+    }
+
+    /// Line of the current token, to locate errors.
+    pub fn line(&self) -> usize {
+        self.lexer.line()
+    }
+
+    /// Consume the current token inside an item, which drops the `##` comments before it.
+    fn next_token(&mut self) -> Result<Token> {
+        self.peek_token()?;
+        self.pending_doc.clear();
+        self.lexer.next_token()
+    }
+
+    fn nested_definition(&mut self) -> Result<Expr> {
+        let function = self.function()?;
+        self.eat(Token::In)?;
+        let body = self.expr()?;
+        Ok(Expr::Def(Box::new(function), Box::new(body)))
+    }
+
+    /// Parse the parameter names, each optionally followed by a type like `c: i32` and by a
+    /// default value like `y = 2`.
+    fn parameters(&mut self) -> Result<Parameters> {
         let mut params = vec![];
+        let mut types = vec![];
+        let mut defaults = vec![];
         loop {
-            match *self.lexer.peek()? {
+            match *self.peek_token()? {
                 Token::Identifier(_) => {
                     let ident =
-                        match self.lexer.next_token()? {
+                        match self.next_token()? {
                             Token::Identifier(ident) => ident,
                             _ => unreachable!(),
                         };
+                    types.push(self.type_annotation()?);
+                    let default =
+                        if *self.peek_token()? == Token::Equal {
+                            self.eat(Token::Equal)?;
+                            let default = self.expr()?;
+                            // The defaults are copied to the call sites, which cannot lift a function.
+                            if default.contains_definition() {
+                                return Err(DefinitionInDefault(ident.to_string()));
+                            }
+                            Some(default)
+                        }
+                        else {
+                            None
+                        };
+                    if default.is_none() && defaults.iter().any(Option::is_some) {
+                        return Err(RequiredAfterDefault(ident.to_string()));
+                    }
                     params.push(ident);
+                    defaults.push(default);
                 },
                 _ => break,
             }
         }
-        Ok(params)
+        // The caller evaluates the defaults in its scope, where these names mean something else.
+        for (param, default) in params.iter().zip(&defaults) {
+            if let Some(ref default) = *default {
+                if let Some(parameter) = params.iter().find(|parameter| default.uses_variable(parameter)) {
+                    return Err(DefaultUsesParameter(param.to_string(), parameter.to_string()));
+                }
+            }
+        }
+        Ok((params, types, defaults))
+    }
+
+    /// Require `;` after the definitions and the externs, which is optional by default.
+    pub fn set_strict_semicolons(&mut self, strict: bool) {
+        self.strict_semicolons = strict;
+    }
+
+    /// Look at the current token without consuming it, where an item can start.
+    pub fn peek(&mut self) -> Result<&Token> {
+        // The previous item can end after looking past the doc comments of this one.
+        for text in mem::take(&mut self.pending_doc) {
+            self.add_doc(text);
+        }
+        self.lexer.peek()
+    }
+
+    /// Look at the current token inside an item, skipping the `##` comments before it.
+    fn peek_token(&mut self) -> Result<&Token> {
+        while let Token::DocComment(_) = *self.lexer.peek()? {
+            if let Token::DocComment(text) = self.lexer.next_token()? {
+                self.pending_doc.push(text);
+            }
+        }
+        self.lexer.peek()
+    }
+
+    /// Get the precedence of every operator, from the loosest to the tightest.
+    pub fn precedences(&self) -> Vec<(BinaryOp, i32)> {
+        let mut precedences: Vec<_> = self.bin_precedence.iter()
+            .map(|(&op, &precedence)| (op, precedence))
+            .collect();
+        precedences.sort_by_key(|&(op, precedence)| (precedence, op));
+        precedences
     }
 
     fn precedence(&self, op: BinaryOp) -> Result<i32> {
         match self.bin_precedence.get(&op) {
             Some(&precedence) => Ok(precedence),
-            None => Err(Undefined("operator")),
+            None => Err(UndefinedOperator),
         }
     }
 
     fn primary(&mut self) -> Result<Expr> {
-        match *self.lexer.peek()? {
+        match *self.peek_token()? {
             Token::Number(number) => {
-                self.lexer.next_token()?;
+                self.next_token()?;
                 Ok(Expr::Number(number))
             },
             Token::OpenParen => {
                 self.eat(Token::OpenParen)?;
+                if *self.peek_token()? == Token::CloseParen {
+                    self.next_token()?;
+                    return Err(EmptyParens);
+                }
                 let expr = self.expr()?;
                 self.eat(Token::CloseParen)?;
                 Ok(expr)
             },
+            Token::Positional(index) => {
+                self.next_token()?;
+                if index == 0 {
+                    return Err(Unexpected("`$0`, positional parameters start at `$1`"));
+                }
+                Ok(Expr::PositionalArg(index))
+            },
             Token::Identifier(_) => self.ident_expr(),
-            _ => Err(Unexpected("token when expecting an expression")),
+            Token::Def => self.nested_definition(),
+            ref token => Err(Expected("an expression: a number, an identifier, `(` or `def`".to_string(), token.clone())),
         }
     }
 
     fn prototype(&mut self) -> Result<Prototype> {
         let function_name = self.ident()?;
         self.eat(Token::OpenParen)?;
-        let parameters = self.parameters()?;
+        let (parameters, parameter_types, defaults) = self.parameters()?;
         self.eat(Token::CloseParen)?;
+        let return_type = self.return_type()?;
 
         Ok(Prototype {
+            defaults,
+            doc: self.doc.take(),
             function_name,
+            inline: false,
             parameters,
+            parameter_types,
+            return_type,
         })
     }
 
+    /// Parse the optional return type following the parameters, which must be on the same line.
+    ///
+    /// This way, the REPL compiles an `extern` as soon as its line is entered, instead of waiting
+    /// for the next line to find out whether it starts with a `:`.
+    fn return_type(&mut self) -> Result<Type> {
+        if self.lexer.at_end_of_line()? {
+            return Ok(Type::F64);
+        }
+        self.type_annotation()
+    }
+
+    /// Parse a top-level expression as an anonymous function, whose parameters are the positional
+    /// parameters `$1` to `$N` it references.
     pub fn toplevel(&mut self) -> Result<Function> {
+        // Expressions are not documented.
+        self.doc = None;
         let body = self.expr()?;
         self.index += 1;
+        let arity = body.max_positional_arg();
         Ok(Function {
             body,
             prototype: Prototype {
-                function_name: format!("__anon_{}", self.index),
-                parameters: vec![],
+                defaults: vec![None; arity],
+                doc: None,
+                function_name: format!("__anon_{}", self.index).into(),
+                inline: false,
+                parameters: (1..=arity).map(positional_parameter).collect(),
+                parameter_types: vec![Type::F64; arity],
+                return_type: Type::F64,
             },
         })
     }
+
+    /// Change the precedence of `op` for the expressions parsed afterwards.
+    pub fn set_precedence(&mut self, op: BinaryOp, precedence: i32) {
+        self.bin_precedence.insert(op, precedence);
+    }
+
+    fn type_(&mut self) -> Result<Type> {
+        let name = self.ident()?;
+        Type::from_name(&name).ok_or_else(|| UnknownType(name.to_string()))
+    }
+
+    /// Parse an optional `: type`, defaulting to `f64`.
+    fn type_annotation(&mut self) -> Result<Type> {
+        if *self.peek_token()? == Token::Colon {
+            self.eat(Token::Colon)?;
+            self.type_()
+        }
+        else {
+            Ok(Type::F64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read};
+
+    use crate::ast::{BinaryOp, Expr, Type};
+    use crate::error::Error;
+    use crate::lexer::{Lexer, Token};
+
+    use super::Parser;
+
+    /// Input typed in the REPL, where reading past the lines entered so far would block.
+    struct Lines(&'static [u8]);
+
+    impl Read for Lines {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "waiting for the next line"));
+            }
+            self.0.read(buffer)
+        }
+    }
+
+    fn parser(source: &str) -> Parser<&[u8]> {
+        Parser::new(Lexer::new(source.as_bytes()))
+    }
+
+    /// The caller would look up the parameters under their names in its own scope.
+    #[test]
+    fn default_uses_parameter() {
+        let error = parser("def f(x y = x) x + y").definition().expect_err("error");
+        assert!(matches!(error, Error::DefaultUsesParameter(ref name, ref parameter)
+            if name == "y" && parameter == "x"));
+        let error = parser("def f(x y = z * 2 z = 1) x").definition().expect_err("error");
+        assert_eq!(format!("{:?}", error),
+            "the default value of parameter `y` is evaluated by the caller, so it cannot refer to parameter `z`");
+        assert!(parser("def f(x y = a + pi) x").definition().is_ok());
+    }
+
+    #[test]
+    fn default_with_definition() {
+        let error = parser("def f(x y = def g(z) z in g(1)) x + y").definition().expect_err("error");
+        assert!(matches!(error, Error::DefinitionInDefault(ref name) if name == "y"));
+        assert_eq!(format!("{:?}", error), "the default value of parameter `y` cannot define a function");
+        assert!(parser("def f(x y = 1 + (def g(z) z in g(1))) x").definition().is_err());
+    }
+
+    #[test]
+    fn doc_comment() {
+        let source = "## Square `x`.\n## Twice.\ndef square(x) x * x;\nextern {\n## Sine.\nsin(x)\ncos(x) }";
+        let mut parser = parser(source);
+        while let Token::DocComment(_) = *parser.peek().unwrap() {
+            parser.doc_comment().unwrap();
+        }
+        let definition = parser.definition().unwrap();
+        assert_eq!(definition.prototype.doc.as_deref(), Some("Square `x`.\nTwice."));
+        parser.advance().unwrap();
+        let prototypes = parser.extern_().unwrap();
+        assert_eq!(prototypes[0].doc.as_deref(), Some("Sine."));
+        assert_eq!(prototypes[1].doc, None);
+    }
+
+    /// Inside an item, `##` starts a plain comment.
+    #[test]
+    fn doc_comment_in_definition() {
+        let mut parser = parser("def f(x)\n## The body.\nx + ## One.\n1;");
+        let definition = parser.definition().unwrap();
+        assert_eq!(definition.prototype.doc, None);
+        assert!(matches!(definition.body, Expr::Binary(..)));
+        assert_eq!(*parser.peek().unwrap(), Token::SemiColon);
+    }
+
+    /// Without `;`, the end of `f` is only found after looking past the doc comment of `g`.
+    #[test]
+    fn doc_comment_without_semicolon() {
+        let mut parser = parser("def f(x) x\n## Doc for g\ndef g(x) ## Not a doc.\n  x\ndef h(x) x");
+        assert_eq!(parser.definition().unwrap().prototype.doc, None);
+        assert_eq!(*parser.peek().unwrap(), Token::Def);
+        assert_eq!(parser.definition().unwrap().prototype.doc.as_deref(), Some("Doc for g"));
+        assert_eq!(*parser.peek().unwrap(), Token::Def);
+        assert_eq!(parser.definition().unwrap().prototype.doc, None);
+    }
+
+    #[test]
+    fn empty_body() {
+        assert!(matches!(parser("def f(x);").definition(), Err(Error::EmptyBody(ref name)) if name == "f"));
+        assert!(matches!(parser("def f(x)").definition(), Err(Error::EmptyBody(ref name)) if name == "f"));
+    }
+
+    #[test]
+    fn empty_parens() {
+        assert!(matches!(parser("()").toplevel(), Err(Error::EmptyParens)));
+        assert!(matches!(parser("(1)").toplevel().unwrap().body, Expr::Number(number) if number == 1.0));
+    }
+
+    #[test]
+    fn error_line() {
+        let mut definition = parser("def f(x) # First line.\n\n  x + );");
+        assert!(matches!(definition.definition(), Err(Error::Expected(_, Token::CloseParen))));
+        assert_eq!(definition.line(), 3);
+        let mut continued = parser("def f(x) x +\n  \\\n  );");
+        assert!(continued.definition().is_err());
+        assert_eq!(continued.line(), 3);
+        let mut include = parser("include \"a\nb\"\n(1 + );");
+        include.include().unwrap();
+        assert!(include.toplevel().is_err());
+        assert_eq!(include.line(), 3);
+    }
+
+    #[test]
+    fn expected() {
+        let error = parser("def 1(x) x").definition().expect_err("error");
+        assert!(matches!(error, Error::Expected(ref expected, Token::Number(_)) if expected == "an identifier"));
+        assert_eq!(format!("{:?}", error), "expected an identifier, found number `1`");
+        let error = parser("def f x").definition().expect_err("error");
+        assert_eq!(format!("{:?}", error), "expected `(`, found identifier `x`");
+        let error = parser(";").toplevel().expect_err("error");
+        assert!(matches!(error, Error::Expected(_, Token::SemiColon)));
+        assert_eq!(format!("{:?}", error), "expected an expression: a number, an identifier, `(` or `def`, found `;`");
+    }
+
+    #[test]
+    fn many_definitions() {
+        let source = "def f(x) x * x + 1;\n".repeat(10_000);
+        let mut parser = parser(&source);
+        while *parser.peek().unwrap() != Token::Eof {
+            parser.definition().unwrap();
+            parser.advance().unwrap();
+        }
+        assert_eq!(parser.bin_precedence.len(), 4);
+        assert_eq!(parser.index, 0);
+        assert!(parser.doc.is_none());
+    }
+
+    #[test]
+    fn recover() {
+        let mut strict = parser("def square(x) x * x\ndef cube(x) x * x * x;");
+        strict.set_strict_semicolons(true);
+        assert!(matches!(strict.definition(), Err(Error::Expected(_, Token::Def))));
+        // The `def` of the next item is kept.
+        strict.recover().unwrap();
+        assert_eq!(&*strict.definition().unwrap().prototype.function_name, "cube");
+        let mut expression = parser("1 + ) 2 @ 3; 4");
+        assert!(expression.toplevel().is_err());
+        expression.recover().unwrap();
+        assert!(matches!(expression.toplevel().unwrap().body, Expr::Number(number) if number == 4.0));
+        // In the REPL, neither the end of an item nor the recovery reads the next line.
+        let mut parser = Parser::new(Lexer::new(Lines(b"extern sin(x)\n")));
+        assert!(parser.extern_().is_ok());
+        let mut parser = Parser::new(Lexer::new(Lines(b"def f(x) )  # Comment.\n")));
+        assert!(parser.definition().is_err());
+        parser.recover().unwrap();
+    }
+
+    /// Drive the parser like the REPL does, skipping the rest of the item on which it fails.
+    #[test]
+    fn repl_loop() {
+        let mut parser = parser("def f(x) x; extern sin(x) f(1); ) 2; 3");
+        let mut items = vec![];
+        loop {
+            let item =
+                match *parser.peek().unwrap() {
+                    Token::Eof => break,
+                    Token::SemiColon => {
+                        parser.advance().unwrap();
+                        continue;
+                    },
+                    Token::Def => parser.definition().map(|_| "definition"),
+                    Token::Extern => parser.extern_().map(|_| "extern"),
+                    _ => parser.toplevel().map(|_| "expression"),
+                };
+            match item {
+                Ok(item) => items.push(item),
+                Err(_) => {
+                    items.push("error");
+                    parser.recover().unwrap();
+                },
+            }
+        }
+        assert_eq!(items, ["definition", "extern", "expression", "error", "expression"]);
+    }
+
+    #[test]
+    fn return_type() {
+        let prototypes = parser("extern f(x): i32").extern_().unwrap();
+        assert_eq!(prototypes[0].return_type, Type::I32);
+        assert_eq!(prototypes[0].to_string(), "f(x): i32");
+        assert_eq!(parser("g(x) : f64").prototype().unwrap().return_type, Type::F64);
+        // The prototype is complete at the end of its line, without reading the next one.
+        let mut parser = Parser::new(Lexer::new(Lines(b"cos(x)  # Cosine.\n")));
+        assert_eq!(parser.prototype().unwrap().return_type, Type::F64);
+    }
+
+    #[test]
+    fn set_precedence() {
+        let mut parser = parser("1 + 2 * 3; 1 + 2 * 3;");
+        assert_eq!(parser.toplevel().unwrap().body.to_string(), "(1 + (2 * 3))");
+        parser.advance().unwrap();
+        parser.set_precedence(BinaryOp::Plus, 50);
+        assert_eq!(parser.toplevel().unwrap().body.to_string(), "((1 + 2) * 3)");
+        let precedences = parser.precedences();
+        assert_eq!(precedences.last(), Some(&(BinaryOp::Plus, 50)));
+        assert_eq!(precedences[0], (BinaryOp::LessThan, 10));
+    }
+
+    #[test]
+    fn trailing_comma() {
+        let body = parser("foo(1, 2,)").toplevel().unwrap().body;
+        assert!(matches!(body, Expr::Call(ref name, ref args) if &**name == "foo" && args.len() == 2));
+        assert!(matches!(parser("foo(,)").toplevel(), Err(Error::Unexpected(message)) if message.contains("`,`")));
+        assert!(matches!(parser("foo(1,,)").toplevel(), Err(Error::Expected(_, Token::Comma))));
+    }
 }
@@ -0,0 +1,48 @@
+use std::io::stdin;
+
+/// Functions callable from Kaleidoscope after declaring them with `extern`.
+///
+/// The JIT only looks up symbols in the process when they are not registered, and the symbols of
+/// an executable are usually not exported, so every function of the runtime must be listed here.
+pub fn symbols() -> Vec<(&'static str, *const u8)> {
+    vec![
+        ("putchard", putchard as *const u8),
+        ("read", read as *const u8),
+    ]
+}
+
+/// Print the character whose Unicode code point is `char`, rounded, followed by a newline.
+///
+/// Values that are not a code point, like negative numbers or NaN, print the replacement
+/// character `�` instead of being truncated to another character.
+#[no_mangle]
+pub extern "C" fn putchard(char: f64) -> f64 {
+    let code_point = char.round();
+    let char =
+        if (0.0..=u32::MAX as f64).contains(&code_point) {
+            char::from_u32(code_point as u32)
+        }
+        else {
+            None
+        };
+    println!("{}", char.unwrap_or(char::REPLACEMENT_CHARACTER));
+    0.0
+}
+
+/// Read a number on a line of the standard input, returning NaN if it is not a number.
+///
+/// Blank lines are skipped, which also skips the end of the line calling `read()` in the REPL.
+///
+/// This is not `#[no_mangle]` since it would replace the `read` function of the C library, used
+/// by the standard library itself: it is only found through the symbol table of the JIT.
+pub extern "C" fn read() -> f64 {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => return f64::NAN,
+            Ok(_) if line.trim().is_empty() => continue,
+            Ok(_) => return line.trim().parse().unwrap_or(f64::NAN),
+        }
+    }
+}
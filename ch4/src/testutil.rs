@@ -0,0 +1,94 @@
+use std::io;
+
+use cranelift_module::Linkage;
+
+use crate::ast::{
+    Expr,
+    Function,
+    Prototype,
+    Type,
+};
+use crate::error::Result;
+use crate::gen::Generator;
+use crate::lexer::{Lexer, Token};
+use crate::parser::Parser;
+
+/// Compile every item of `source`, then call the function `entry` with `args`.
+///
+/// The call goes through a generated zero-argument wrapper, so the argument count is checked by
+/// the code generator like any other call. The IR is discarded instead of being printed.
+pub fn run(source: &str, entry: &str, args: &[f64]) -> Result<f64> {
+    let lexer = Lexer::new(source.as_bytes());
+    let mut parser = Parser::new(lexer);
+    let mut generator = Generator::new();
+    generator.print_ir_to(Box::new(io::sink()));
+    loop {
+        match *parser.peek()? {
+            Token::Eof => break,
+            Token::SemiColon => {
+                parser.advance()?;
+            },
+            Token::DocComment(_) => parser.doc_comment()?,
+            Token::Def | Token::Inline => {
+                let definition = parser.definition()?;
+                generator.function(definition)?;
+            },
+            Token::Extern => {
+                for prototype in parser.extern_()? {
+                    generator.prototype(&prototype, Linkage::Import)?;
+                }
+            },
+            _ => {
+                let expr = parser.toplevel()?;
+                generator.function(expr)?;
+            },
+        }
+    }
+
+    let args = args.iter().map(|&arg| Expr::Number(arg)).collect();
+    let wrapper = Function {
+        body: Expr::Call(entry.into(), args),
+        prototype: Prototype {
+            defaults: vec![],
+            doc: None,
+            function_name: "__anon_entry".into(),
+            inline: false,
+            parameters: vec![],
+            parameter_types: vec![],
+            return_type: Type::F64,
+        },
+    };
+    let function = generator.function(wrapper)?;
+    function.call(&[])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ErrorKind;
+
+    use super::run;
+
+    #[test]
+    fn call_entry() {
+        assert_eq!(run("def add(x y) x + y", "add", &[1.0, 2.0]).unwrap(), 3.0);
+        assert_eq!(run("def f() 4; def g() f() * 2", "g", &[]).unwrap(), 8.0);
+    }
+
+    #[test]
+    fn wrong_argument_count() {
+        let error = run("def f(x) x", "f", &[]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ArgCount);
+    }
+
+    #[test]
+    fn undefined_entry() {
+        let error = run("def f(x) x", "g", &[1.0]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UndefinedFunction);
+    }
+
+    #[test]
+    fn syntax_error() {
+        let error = run("def f(x x", "f", &[1.0]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedToken);
+    }
+}
@@ -0,0 +1,140 @@
+use crate::ast::{Expr, Function};
+
+/// Traversal of the AST, to write analyses without repeating the recursion.
+///
+/// Implementors override the methods for the nodes they care about and call the matching `walk_`
+/// function to keep visiting the children.
+pub trait Visitor {
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr);
+    }
+
+    fn visit_function(&mut self, function: &Function) {
+        walk_function(self, function);
+    }
+}
+
+/// Visitor allowed to modify the nodes in place.
+pub trait MutVisitor {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        walk_expr_mut(self, expr);
+    }
+
+    fn visit_function_mut(&mut self, function: &mut Function) {
+        walk_function_mut(self, function);
+    }
+}
+
+pub fn walk_expr<V: Visitor + ?Sized>(visitor: &mut V, expr: &Expr) {
+    match *expr {
+        Expr::Binary(_, ref left, ref right) => {
+            visitor.visit_expr(left);
+            visitor.visit_expr(right);
+        },
+        Expr::Call(_, ref args) => {
+            for arg in args {
+                visitor.visit_expr(arg);
+            }
+        },
+        Expr::Def(ref function, ref body) => {
+            visitor.visit_function(function);
+            visitor.visit_expr(body);
+        },
+        Expr::Number(_) | Expr::PositionalArg(_) | Expr::Variable(_) => (),
+    }
+}
+
+pub fn walk_function<V: Visitor + ?Sized>(visitor: &mut V, function: &Function) {
+    for default in function.prototype.defaults.iter().flatten() {
+        visitor.visit_expr(default);
+    }
+    visitor.visit_expr(&function.body);
+}
+
+pub fn walk_expr_mut<V: MutVisitor + ?Sized>(visitor: &mut V, expr: &mut Expr) {
+    match *expr {
+        Expr::Binary(_, ref mut left, ref mut right) => {
+            visitor.visit_expr_mut(left);
+            visitor.visit_expr_mut(right);
+        },
+        Expr::Call(_, ref mut args) => {
+            for arg in args {
+                visitor.visit_expr_mut(arg);
+            }
+        },
+        Expr::Def(ref mut function, ref mut body) => {
+            visitor.visit_function_mut(function);
+            visitor.visit_expr_mut(body);
+        },
+        Expr::Number(_) | Expr::PositionalArg(_) | Expr::Variable(_) => (),
+    }
+}
+
+pub fn walk_function_mut<V: MutVisitor + ?Sized>(visitor: &mut V, function: &mut Function) {
+    for default in function.prototype.defaults.iter_mut().flatten() {
+        visitor.visit_expr_mut(default);
+    }
+    visitor.visit_expr_mut(&mut function.body);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::{Expr, Function};
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    use super::{MutVisitor, Visitor, walk_expr, walk_expr_mut};
+
+    fn definition(source: &str) -> Function {
+        Parser::new(Lexer::new(source.as_bytes())).definition().unwrap()
+    }
+
+    struct BinaryCount(usize);
+
+    impl Visitor for BinaryCount {
+        fn visit_expr(&mut self, expr: &Expr) {
+            if let Expr::Binary(..) = *expr {
+                self.0 += 1;
+            }
+            walk_expr(self, expr);
+        }
+    }
+
+    struct Double;
+
+    impl MutVisitor for Double {
+        fn visit_expr_mut(&mut self, expr: &mut Expr) {
+            if let Expr::Number(ref mut number) = *expr {
+                *number *= 2.0;
+            }
+            walk_expr_mut(self, expr);
+        }
+    }
+
+    struct Numbers(Vec<f64>);
+
+    impl Visitor for Numbers {
+        fn visit_expr(&mut self, expr: &Expr) {
+            if let Expr::Number(number) = *expr {
+                self.0.push(number);
+            }
+            walk_expr(self, expr);
+        }
+    }
+
+    #[test]
+    fn count_binary() {
+        let mut count = BinaryCount(0);
+        count.visit_function(&definition("def f(x) x * 2 + g(x - 1, def h(y) y < 1 in h(x))"));
+        assert_eq!(count.0, 4);
+    }
+
+    #[test]
+    fn mut_visitor() {
+        let mut function = definition("def f(x) x * 2 + g(3)");
+        Double.visit_function_mut(&mut function);
+        let mut numbers = Numbers(vec![]);
+        numbers.visit_function(&function);
+        assert_eq!(numbers.0, [4.0, 6.0]);
+    }
+}
@@ -0,0 +1,505 @@
+//! Run the compiler on the example programs of this directory.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::{self, Command, Stdio};
+
+/// Output of a run of the compiler.
+struct Output {
+    code: Option<i32>,
+    stderr: String,
+    stdout: String,
+}
+
+/// Run the compiler with `args` from the crate directory, writing `stdin` to its standard input.
+///
+/// The IR is discarded, so that the standard output only holds what the program prints.
+fn kaleidoscope(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ch4"))
+        .args(["--emit-ir-file", "/dev/null"])
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("run the compiler");
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    Output {
+        code: output.status.code(),
+        stderr: String::from_utf8(output.stderr).unwrap(),
+        stdout: String::from_utf8(output.stdout).unwrap(),
+    }
+}
+
+#[test]
+fn discard_results() {
+    let output = kaleidoscope(&["tests/hello.kal"], "");
+    assert_eq!(output.stdout, "extern putchard(char): f64\nH\n0\ni\n0\n");
+    let output = kaleidoscope(&["--discard-results", "tests/hello.kal"], "");
+    assert_eq!(output.stdout, "extern putchard(char): f64\nH\ni\n");
+    assert_eq!(output.code, Some(0));
+}
+
+/// The language has no conditional to stop a recursion, so this calls through a long chain of
+/// functions instead.
+#[test]
+fn stack_size() {
+    let mut source = "def f0() 0;\n".to_string();
+    for index in 1..=500 {
+        source.push_str(&format!("def f{}() f{}() + 1;\n", index, index - 1));
+    }
+    source.push_str("f500();\n");
+    let output = kaleidoscope(&["--stack-size", "16777216"], &source);
+    assert!(output.stdout.ends_with("ready> 500\nready> "), "{}", output.stdout);
+    assert_eq!(output.stderr, "");
+
+    let output = kaleidoscope(&["--stack-size", "big"], "");
+    assert_eq!(output.stderr, "Error: invalid value `big` for option `--stack-size`\n");
+    assert_eq!(output.code, Some(1));
+}
+
+#[test]
+fn prelude() {
+    let output = kaleidoscope(&["--prelude", "tests/prelude.kal"], "cube(3);\n");
+    assert_eq!(output.stdout, "ready> 27\nready> ");
+}
+
+#[test]
+fn dump_tokens() {
+    let output = kaleidoscope(&["--dump-tokens"], "def f(x) x + 1.5;");
+    let tokens = [
+        "Def",
+        "Identifier(\"f\")",
+        "OpenParen",
+        "Identifier(\"x\")",
+        "CloseParen",
+        "Identifier(\"x\")",
+        "Plus",
+        "Number(1.5)",
+        "SemiColon",
+        "Eof",
+    ];
+    assert_eq!(output.stdout, tokens.iter().map(|token| format!("{}\n", token)).collect::<String>());
+}
+
+#[test]
+fn max_errors() {
+    let output = kaleidoscope(&["--max-errors", "3"], "x; x; x; x; x;\n");
+    let error = "Compile error at line 1: undefined variable `x`\n";
+    assert_eq!(output.stderr, format!("{}Error: aborting after 3 errors\n", error.repeat(3)));
+    assert_eq!(output.code, Some(1));
+    let output = kaleidoscope(&["--max-errors", "1"], "x; x;\n");
+    assert_eq!(output.stderr, format!("{}Error: aborting after 1 error\n", error));
+    // A limit of 0 is rejected instead of aborting on the first error.
+    let output = kaleidoscope(&["--max-errors", "0"], "x;\n");
+    assert_eq!(output.stderr, "Error: invalid value `0` for option `--max-errors`\n");
+    assert_eq!(output.code, Some(1));
+}
+
+#[test]
+fn line_continuation() {
+    let output = kaleidoscope(&[], "(1 +\n2) * \\\n3;\n");
+    assert_eq!(output.stdout, "ready> 9\nready> ");
+}
+
+#[test]
+fn type_command() {
+    let output = kaleidoscope(&[], ":type 1 + 2 * 3;\n:type def f(x) x in f(1);\n");
+    assert_eq!(output.stdout, "ready> f64\nready> f64\nready> ");
+}
+
+#[test]
+fn extern_echo() {
+    let output = kaleidoscope(&[], "extern sin(x);\nextern putchar(c: i32): i32;\nextern { cos(x); pow(x y) }\n");
+    let expected = "ready> extern sin(x): f64\nready> extern putchar(c: i32): i32\n\
+        ready> extern cos(x): f64\nextern pow(x y): f64\nready> ";
+    assert_eq!(output.stdout, expected);
+}
+
+#[test]
+fn last_result() {
+    let output = kaleidoscope(&[], "2+3;\n_*2;\n");
+    assert_eq!(output.stdout, "ready> 5\nready> 10\nready> ");
+}
+
+/// `putchar` writes to the buffer of the C library, which is only flushed at exit.
+#[test]
+fn putchar() {
+    let output = kaleidoscope(&["tests/putchar.kal"], "");
+    assert_eq!(output.stdout, "extern putchar(c: i32): i32\n72\n105\n10\nHi\n");
+    let output = kaleidoscope(&[], "extern putchar(c: i32): i32;\nputchar(nan);\nputchar(0 - inf);\n");
+    assert_eq!(output.code, Some(0));
+    // The arguments saturate to 0 and `i32::MIN`, whose low byte is 0.
+    let expected = "ready> extern putchar(c: i32): i32\nready> 0\nready> 0\n";
+    assert!(output.stdout.starts_with(expected), "{:?}", output.stdout);
+}
+
+#[test]
+fn read() {
+    let output = kaleidoscope(&["tests/read.kal"], "\n21\n");
+    assert_eq!(output.stdout, "extern read(): f64\n42\n");
+    let output = kaleidoscope(&["tests/read.kal"], "twenty\n");
+    assert_eq!(output.stdout, "extern read(): f64\nNaN\n");
+}
+
+#[test]
+fn include() {
+    let output = kaleidoscope(&["tests/include.kal"], "");
+    assert_eq!(output.stdout, "27\n");
+
+    let directory = env::temp_dir().join(format!("kaleidoscope-include-{}", process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("cycle.kal");
+    fs::write(&path, "include \"cycle.kal\";\n").unwrap();
+    let path = path.canonicalize().unwrap();
+    let output = kaleidoscope(&[path.to_str().unwrap()], "");
+    fs::remove_dir_all(&directory).unwrap();
+    assert_eq!(output.stderr, format!("I/O error at line 1: `{}` includes itself\n", path.display()));
+    assert_eq!(output.code, Some(1));
+}
+
+#[test]
+fn print_result_as_hex() {
+    let output = kaleidoscope(&["--print-result-as-hex"], "0;\n1.5;\n0 - 2;\n");
+    let expected = "ready> 0 (0x0000000000000000)\nready> 1.5 (0x3ff8000000000000)\n\
+        ready> -2 (0xc000000000000000)\nready> ";
+    assert_eq!(output.stdout, expected);
+}
+
+#[test]
+fn reset() {
+    let output = kaleidoscope(&[], "def f(x) x;\ndef f(x y) x + y;\n:reset\ndef f(x y) x + y;\nf(1, 2);\n");
+    let expected = "Compile error at line 2: redefinition of function with different number of parameters\n";
+    assert_eq!(output.stderr, expected);
+    let expected = "ready> ready> ready> All definitions were removed\nready> ready> 3\nready> ";
+    assert_eq!(output.stdout, expected);
+}
+
+#[test]
+fn parse_only() {
+    let output = kaleidoscope(&["--parse-only"], "def f(x y = 2) x * 2 + y;\nextern sin(x);\nf(3);\n");
+    assert_eq!(output.stdout, "def f(x y = 2): f64 ((x * 2) + y)\nextern sin(x): f64\nf(3)\n");
+    assert_eq!(output.code, Some(0));
+
+    let output = kaleidoscope(&["--parse-only"], "def f(x) x +;\n1;\n");
+    assert_eq!(output.stdout, "1\n");
+    assert!(output.stderr.starts_with("Parse error at line 1: expected an expression"));
+    assert_eq!(output.code, Some(1));
+}
+
+#[test]
+fn putchard() {
+    let calls = ["65", "64.6", "233", "0 - 1", "1114112", "nan"];
+    let input: String = calls.iter().map(|arg| format!("putchard({});\n", arg)).collect();
+    let output = kaleidoscope(&[], &format!("extern putchard(char);\n{}", input));
+    // Each character follows a prompt, after the echo of the `extern`.
+    let printed: Vec<_> = output.stdout.lines()
+        .filter_map(|line| line.strip_prefix("ready> "))
+        .skip(1)
+        .take(calls.len())
+        .collect();
+    assert_eq!(printed, ["A", "A", "é", "\u{fffd}", "\u{fffd}", "\u{fffd}"]);
+}
+
+/// The IR is written out before exiting with the status of the entry point or of the errors.
+#[test]
+fn emit_ir_file() {
+    let directory = env::temp_dir().join(format!("kaleidoscope-ir-{}", process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let ir_path = directory.join("ir.txt");
+    let ir_path = ir_path.to_str().unwrap();
+    let output = kaleidoscope(&["--emit-ir-file", ir_path, "--entry", "main", "tests/main.kal"], "");
+    assert_eq!(output.code, Some(42));
+    let ir = fs::read_to_string(ir_path).unwrap();
+    assert!(ir.starts_with("function u0:0() -> f64"), "{:?}", ir);
+
+    let source = directory.join("errors.kal");
+    fs::write(&source, "def square(x) x * x;\ndef twice(x) x + x;\ndef broken() y;\n").unwrap();
+    let output = kaleidoscope(&["--emit-ir-file", ir_path, source.to_str().unwrap()], "");
+    let ir = fs::read_to_string(ir_path).unwrap();
+    fs::remove_dir_all(&directory).unwrap();
+    assert_eq!(output.code, Some(1));
+    assert_eq!(ir.matches("function u0:").count(), 2, "{:?}", ir);
+}
+
+#[test]
+fn define() {
+    let args = ["--define", "DEBUG=1", "--define", "SCALE=2.5"];
+    let output = kaleidoscope(&args, "def f(x) x * SCALE + DEBUG;\nf(2);\n");
+    assert_eq!(output.stdout, "ready> ready> 6\nready> ");
+    let output = kaleidoscope(&["--define", "DEBUG"], "");
+    assert_eq!(output.stderr, "Error: invalid value `DEBUG` for option `--define`\n");
+    assert_eq!(output.code, Some(1));
+}
+
+#[test]
+fn strict() {
+    let output = kaleidoscope(&["--strict", "tests/strict.kal"], "");
+    let expected = "Parse error at line 3: \
+        top-level expressions are not allowed in strict mode, only `def` and `extern`\n";
+    assert_eq!(output.stderr, expected);
+    assert_eq!(output.code, Some(1));
+    let output = kaleidoscope(&["--strict", "--entry", "main", "tests/main.kal"], "");
+    assert_eq!(output.stderr, "");
+    assert_eq!(output.code, Some(42));
+    let output = kaleidoscope(&["tests/strict.kal"], "");
+    assert_eq!(output.stdout, "3\n");
+    assert_eq!(output.code, Some(0));
+}
+
+#[test]
+fn entry() {
+    let output = kaleidoscope(&["--entry", "start", "--discard-results", "tests/entry.kal"], "");
+    assert_eq!(output.stdout, "10\n");
+    assert_eq!(output.code, Some(10));
+    let output = kaleidoscope(&["--entry", "square", "tests/entry.kal"], "");
+    assert_eq!(output.stderr, "Error: entry point `square` must take no parameters, but takes 1\n");
+    assert_eq!(output.code, Some(1));
+    let output = kaleidoscope(&["--entry", "missing", "tests/entry.kal"], "");
+    assert!(output.stderr.starts_with("Error: undefined function `missing`"), "{}", output.stderr);
+    assert_eq!(output.code, Some(1));
+}
+
+#[test]
+fn prec_command() {
+    let output = kaleidoscope(&[], ":prec\n1 + 2 * 3;\n:prec + 50\n1 + 2 * 3;\n:prec\n:prec + 0\n");
+    let expected = "ready> < 10\n- 20\n+ 20\n* 40\nready> 7\n\
+        ready> ready> 9\n\
+        ready> < 10\n- 20\n* 40\n+ 50\n\
+        ready> ready> ";
+    assert_eq!(output.stdout, expected);
+    let expected = "Parse error at line 6: expected a precedence between 1 and 1000, found number `0`\n";
+    assert_eq!(output.stderr, expected);
+    let output = kaleidoscope(&["tests/precedence.kal"], "");
+    assert_eq!(output.stdout, "14\n20\n< 10\n- 20\n* 40\n+ 50\n");
+    assert_eq!(output.code, Some(0));
+}
+
+#[test]
+fn summary() {
+    let output = kaleidoscope(&["--summary", "tests/summary.kal"], "");
+    assert_eq!(output.stdout, "extern putchard(x): f64\n4\n27\nfunctions: 2, externs: 1, expressions: 2\n");
+    // The expression calling an undefined function is not counted.
+    let output = kaleidoscope(&["--summary"], "def f(x) x;\nf(1);\nextern sin(x);\ng(1);\n");
+    assert!(output.stdout.ends_with("ready> functions: 1, externs: 1, expressions: 1\n"), "{}", output.stdout);
+    let output = kaleidoscope(&["tests/summary.kal"], "");
+    assert!(!output.stdout.contains("functions:"));
+}
+
+#[test]
+fn cfg_command() {
+    let output = kaleidoscope(&["tests/cfg.kal"], "");
+    let expected = "digraph \"u0:0\" {\n    {rank=min; ebb0}\n    ebb0 [shape=record, label=\"{ebb0}\"]\n}\n";
+    assert_eq!(output.stdout, expected);
+}
+
+/// With `-i`, the functions of the file stay available in the REPL that follows.
+#[test]
+fn interactive() {
+    let output = kaleidoscope(&["-i", "tests/include.kal"], "square(5);\ncube(2);\n");
+    assert_eq!(output.stdout, "27\nready> 25\nready> 8\nready> ");
+    let output = kaleidoscope(&["tests/include.kal"], "square(5);\n");
+    assert_eq!(output.stdout, "27\n");
+}
+
+#[test]
+fn unused_parameters() {
+    let output = kaleidoscope(&["tests/unused.kal"], "");
+    assert_eq!(output.stderr, "Warning at line 2: unused parameter `y` in `f`\n");
+    assert_eq!(output.code, Some(0));
+    // Without `;`, parsing `f` reads up to the `def` of `g`, on line 5.
+    let output = kaleidoscope(&[], "def f(x) y\n\n\n\ndef g(x) x\n");
+    let expected = "Warning at line 1: unused parameter `x` in `f`\nCompile error at line 1: undefined variable `y`\n";
+    assert_eq!(output.stderr, expected);
+    let output = kaleidoscope(&["--warnings-as-errors"], "def f(x y) x\n\ndef g(x) x\n");
+    assert_eq!(output.stderr, "Compile error at line 1: unused parameter `y` in `f` (warnings are errors)\n");
+}
+
+#[test]
+fn exit_code() {
+    let output = kaleidoscope(&["--entry", "main", "tests/main.kal"], "");
+    assert_eq!(output.stdout, "42\n");
+    assert_eq!(output.code, Some(42));
+    // The result is truncated, and only its low 8 bits are kept.
+    for &(result, code) in &[("42.9", 42), ("256", 0), ("0 - 1", 255), ("nan", 0)] {
+        let output = kaleidoscope(&["--entry", "main"], &format!("def main() {};\n", result));
+        assert_eq!(output.code, Some(code), "{}", result);
+    }
+}
+
+#[test]
+fn bench_command() {
+    let output = kaleidoscope(&[], ":bench 1+1 1000\n");
+    let report = output.stdout.strip_prefix("ready> 1000 calls in ").expect(&output.stdout);
+    assert!(report.contains(" per call)\n"), "{}", report);
+    let output = kaleidoscope(&["tests/bench.kal"], "");
+    let report = output.stdout.strip_prefix("1000 calls in ").expect(&output.stdout);
+    let (duration, per_call) = report.split_once(" (").expect(report);
+    assert!(duration.ends_with('s') && duration.starts_with(|char: char| char.is_ascii_digit()), "{}", report);
+    assert!(per_call.ends_with("s per call)\n"), "{}", report);
+    assert_eq!(output.code, Some(0));
+    let output = kaleidoscope(&[], ":bench 1+1 0\n");
+    let expected = "Parse error at line 1: expected a number of calls between 1 and 100000000, found number `0`\n";
+    assert_eq!(output.stderr, expected);
+    let output = kaleidoscope(&[], ":bench $1 10\n");
+    assert_eq!(output.stderr, "Compile error at line 1: wrong argument count\n");
+    // The recovery from the error stops at the end of its line.
+    let output = kaleidoscope(&[], ":bench 1+1 0\n:bench 1+1 10\n");
+    assert!(output.stdout.contains("ready> 10 calls in "), "{}", output.stdout);
+}
+
+#[test]
+fn error_stages() {
+    let output = kaleidoscope(&[], "1 @ 2;\ny;\n1 +;\n");
+    let expected = "Lex error at line 1: unknown char `@`\n\
+        Compile error at line 2: undefined variable `y`\n\
+        Parse error at line 3: expected an expression: a number, an identifier, `(` or `def`, found `;`\n";
+    assert_eq!(output.stderr, expected);
+    let output = kaleidoscope(&["tests/errors.kal"], "");
+    let expected = "Lex error at line 2: unknown char `$`\n\
+        Parse error at line 3: expected `)`, found number `1`\n\
+        Compile error at line 4: undefined function `g` (declare it with `extern` or `def` before calling it)\n";
+    assert_eq!(output.stderr, expected);
+    assert_eq!(output.code, Some(1));
+}
+
+#[test]
+fn apply_command() {
+    let output = kaleidoscope(&["tests/positional.kal"], "");
+    assert_eq!(output.stdout, "25\n7\n");
+}
+
+#[test]
+fn dump_tokens_color() {
+    let dump = |no_color: bool| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_ch4"));
+        command.args(["--color", "--dump-tokens"]).stdin(Stdio::piped()).stdout(Stdio::piped());
+        if no_color {
+            command.env("NO_COLOR", "1");
+        }
+        else {
+            command.env_remove("NO_COLOR");
+        }
+        let mut child = command.spawn().expect("run the compiler");
+        child.stdin.take().unwrap().write_all(b"def f").unwrap();
+        String::from_utf8(child.wait_with_output().unwrap().stdout).unwrap()
+    };
+    assert_eq!(dump(false), "\x1b[35mDef\x1b[0m\n\x1b[33mIdentifier(\"f\")\x1b[0m\n\x1b[39mEof\x1b[0m\n");
+    assert_eq!(dump(true), "Def\nIdentifier(\"f\")\nEof\n");
+}
+
+/// The second run finds the definitions stored by the first one.
+#[test]
+fn object_cache() {
+    let directory = env::temp_dir().join(format!("kaleidoscope-object-cache-{}", process::id()));
+    let args = ["--object-cache", directory.to_str().unwrap(), "--summary", "tests/summary.kal"];
+    let first = kaleidoscope(&args, "");
+    let second = kaleidoscope(&args, "");
+    fs::remove_dir_all(&directory).unwrap();
+    assert!(first.stdout.ends_with("object cache: 0 hits, 2 misses\n"), "{}", first.stdout);
+    let expected = "\n27\nfunctions: 2, externs: 1, expressions: 2\nobject cache: 2 hits, 0 misses\n";
+    assert!(second.stdout.ends_with(expected), "{}", second.stdout);
+}
+
+#[test]
+fn defaults() {
+    let output = kaleidoscope(&["tests/defaults.kal"], "");
+    assert_eq!(output.stdout, "5\n13\n");
+    let expected = "Parse error at line 6: parameter `z` needs a default value since it follows a parameter with one\n";
+    assert_eq!(output.stderr, expected);
+    assert_eq!(output.code, Some(1));
+    // The default of `y` is the `a` of the caller, which is used by the call.
+    let output = kaleidoscope(&["--warnings-as-errors"], "def f(x y = a) x + y;\ndef g(a) f(3);\ng(100);\n");
+    assert_eq!(output.stdout, "ready> ready> ready> 103\nready> ");
+    assert_eq!(output.stderr, "");
+    // A default defining a function is rejected instead of crashing the REPL.
+    let output = kaleidoscope(&[], "def f(x y = def g(z) z in g(1)) x + y;\n2;\n");
+    let expected = "Parse error at line 1: the default value of parameter `y` cannot define a function\n";
+    assert_eq!(output.stderr, expected);
+    assert_eq!(output.stdout, "ready> ready> 2\nready> ");
+}
+
+#[test]
+fn warnings_as_errors() {
+    let output = kaleidoscope(&["--warnings-as-errors", "tests/unused.kal"], "");
+    assert_eq!(output.stderr, "Compile error at line 2: unused parameter `y` in `f` (warnings are errors)\n");
+    assert_eq!(output.code, Some(1));
+    // The REPL rejects the definition.
+    let output = kaleidoscope(&["--warnings-as-errors"], "def f(x y) x;\nf(1, 2);\n");
+    assert!(output.stderr.ends_with("Compile error at line 2: undefined function `f` \
+        (declare it with `extern` or `def` before calling it)\n"), "{}", output.stderr);
+}
+
+#[test]
+fn strict_semicolons_recovery() {
+    // The error on `square` keeps the `def` of `cube`.
+    let output = kaleidoscope(&["--strict-semicolons", "tests/semicolons.kal"], "");
+    assert_eq!(output.stderr, "Parse error at line 3: expected `;` after the definition, found `def`\n");
+    assert_eq!(output.stdout, "extern putchard(x): f64\n");
+    assert_eq!(output.code, Some(1));
+    let output = kaleidoscope(&["--strict-semicolons"], "def square(x) x * x\ndef cube(x) x * x * x;\ncube(2);\n");
+    assert_eq!(output.stderr, "Parse error at line 2: expected `;` after the definition, found `def`\n");
+    assert_eq!(output.stdout, "ready> ready> ready> 8\nready> ");
+}
+
+#[test]
+fn error_line_without_semicolon() {
+    // Parsing `f` reads up to the `def` of `g`, on line 5.
+    let output = kaleidoscope(&[], "def f(x) x + y\n\n\n\ndef g(x) x\ng(1) +\n\n  )\n");
+    let expected = "Compile error at line 1: undefined variable `y`\n\
+        Parse error at line 8: expected an expression: a number, an identifier, `(` or `def`, found `)`\n";
+    assert_eq!(output.stderr, expected);
+}
+
+#[test]
+fn special_values() {
+    let output = kaleidoscope(&["tests/special.kal"], "");
+    assert_eq!(output.stdout, "inf\n-inf\nNaN\n1\n0\n0\n");
+    assert_eq!(output.code, Some(0));
+}
+
+#[test]
+fn sizeof() {
+    let output = kaleidoscope(&["tests/sizeof.kal"], "");
+    assert_eq!(output.stdout, "8\n4\n");
+    assert_eq!(output.code, Some(0));
+}
+
+#[test]
+fn inline() {
+    let directory = env::temp_dir().join(format!("kaleidoscope-inline-{}", process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let ir_path = directory.join("ir.txt");
+    let output = kaleidoscope(&["--emit-ir-file", ir_path.to_str().unwrap(), "tests/inline.kal"], "");
+    let ir = fs::read_to_string(&ir_path).unwrap();
+    fs::remove_dir_all(&directory).unwrap();
+    assert_eq!(output.stdout, "13\n13\n");
+    assert_eq!(output.code, Some(0));
+    // The functions are `square`, `square_called`, `sum`, `sum_called`, the two expressions and `recurse`.
+    let calls: Vec<_> = ir.split("function ").skip(1).map(|function| function.matches(" call ").count()).collect();
+    assert_eq!(calls, [0, 0, 0, 2, 1, 1, 1], "{}", ir);
+}
+
+#[test]
+fn cache() {
+    let directory = env::temp_dir().join(format!("kaleidoscope-cache-{}", process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let ir_path = directory.join("ir.txt");
+    let ir_path = ir_path.to_str().unwrap();
+    let mut functions = vec![];
+    for &cache in &[true, false] {
+        let mut args = vec!["--emit-ir-file", ir_path, "tests/cache.kal"];
+        if cache {
+            args.insert(0, "--cache");
+        }
+        let output = kaleidoscope(&args, "");
+        assert_eq!(output.stdout, "9\n27\n");
+        functions.push(fs::read_to_string(ir_path).unwrap().matches("function ").count());
+    }
+    fs::remove_dir_all(&directory).unwrap();
+    // Without `--cache`, the second definition of `square` is compiled again.
+    assert_eq!(functions, [4, 5]);
+}